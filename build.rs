@@ -0,0 +1,6 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tonic_build::compile_protos("proto/feeder.proto")?;
+    // Unchanged copies of the protos of the cln-grpc 0.1.2 crate
+    tonic_build::compile_protos("proto/cln/node.proto")?;
+    Ok(())
+}
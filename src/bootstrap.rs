@@ -0,0 +1,110 @@
+use crate::db::{link_channel_id, query_epoch_count, store_values};
+use crate::rpc::{
+    get_current_channels, get_current_peers, get_forward_fees, get_info, Client, Management,
+};
+use crate::Cli;
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use cln_rpc::primitives::ShortChannelId;
+use log::{debug, info};
+use rusqlite::Connection;
+use std::str::FromStr;
+
+/// The average time between two blocks in seconds
+const BLOCK_INTERVAL: i64 = 600;
+
+/// Sums the fees of forwards into the epochs that ended one, two, ... epoch durations before
+/// `now`, starting with the latest. Epochs that started before the channel was opened are left
+/// out.
+fn past_epochs(
+    forwards: &[(f64, u64)],
+    now: i64,
+    epoch_duration: i64,
+    count: u32,
+    opened: i64,
+) -> Vec<(i64, u64)> {
+    (1..=i64::from(count))
+        .map(|epoch| now - epoch * epoch_duration)
+        .take_while(|end| end - epoch_duration >= opened)
+        .map(|end| {
+            let start = end - epoch_duration;
+            let revenue = forwards
+                .iter()
+                .filter(|(received, _)| *received > start as f64 && *received <= end as f64)
+                .fold(0u64, |sum, (_, fee)| sum.saturating_add(*fee));
+            (end, revenue)
+        })
+        .collect()
+}
+/// Estimates when a channel was opened from the block height in its short channel id
+fn opened_at(id: &str, blockheight: u32, now: i64) -> i64 {
+    id.split('x')
+        .next()
+        .and_then(|block| block.parse::<u32>().ok())
+        .map_or(i64::MIN, |block| {
+            now - i64::from(blockheight.saturating_sub(block)) * BLOCK_INTERVAL
+        })
+}
+/// Reconstructs the past epochs of managed channels without history from their forwards. The
+/// current fee is stored as the fee of every epoch because past fees aren't known. Afterwards
+/// the current epoch ends right away, so the fees are adjusted in the next iteration.
+pub async fn bootstrap(cli: &Cli, client: &mut Client, db: &mut Connection) -> Result<()> {
+    let blockheight = get_info(client).await?.blockheight;
+    let peers = get_current_peers(client).await?;
+    let now = Utc::now().timestamp();
+    let epoch_duration = Duration::hours(cli.epoch_length.into()).num_seconds();
+    for (id, channel) in get_current_channels(peers) {
+        if channel.management != Management::Managed {
+            continue;
+        }
+        link_channel_id(db, &channel.channel_id, &id);
+        if query_epoch_count(&channel.channel_id, db) > 0 {
+            debug!("{}: Not bootstrapping channel with history", id);
+            continue;
+        }
+        let forwards = get_forward_fees(ShortChannelId::from_str(&id).unwrap(), client).await?;
+        let opened = opened_at(&id, blockheight, now);
+        let epochs = past_epochs(&forwards, now, epoch_duration, cli.epochs, opened);
+        for (end, revenue) in &epochs {
+            store_values(
+                db,
+                &channel.channel_id,
+                id.clone(),
+                channel.fee,
+                *revenue,
+                *end,
+            );
+        }
+        info!(
+            "{}: Bootstrapped {} epochs from {} forwards",
+            id,
+            epochs.len(),
+            forwards.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn sum_forwards_into_past_epochs() {
+        let now = 1_660_000_000;
+        let day = 86_400;
+        let forwards = [
+            (now as f64 - 0.5 * day as f64, 1000),
+            (now as f64 - 1.5 * day as f64, 2000),
+            (now as f64 - 1.2 * day as f64, 500),
+            (now as f64 - 3.5 * day as f64, 4000),
+        ];
+        assert_eq!(
+            past_epochs(&forwards, now, day, 3, i64::MIN),
+            vec![(now - day, 2500), (now - 2 * day, 0), (now - 3 * day, 4000)]
+        );
+        // Opened three and a half days ago, so the third epoch is left out
+        let opened = opened_at("1000x1x0", 1504, now);
+        assert_eq!(opened, now - 504 * 600);
+        assert_eq!(past_epochs(&forwards, now, day, 3, opened).len(), 2);
+    }
+}
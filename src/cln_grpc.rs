@@ -0,0 +1,590 @@
+use crate::gossip::GossipChannel;
+use crate::rpc::{NodeInfo, Peer, PeerChannel};
+use anyhow::{anyhow, Context, Result};
+use cln_rpc::model::ListpeersPeersChannelsState;
+use proto::listforwards_request::ListforwardsStatus;
+use proto::listpeers_peers_channels::ListpeersPeersChannelsState as GrpcState;
+use proto::node_client::NodeClient;
+use std::collections::HashMap;
+use std::path::Path;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
+
+/// The feeder only uses a few of the calls of cln-grpc
+#[allow(dead_code)]
+pub mod proto {
+    tonic::include_proto!("cln");
+}
+
+/// Connects to cln-grpc and authenticates with the client certificate. CLN issues the certificate
+/// of cln-grpc for the name `cln`.
+pub async fn connect(
+    endpoint: &str,
+    ca_cert: &Path,
+    client_cert: &Path,
+    client_key: &Path,
+) -> Result<NodeClient<Channel>> {
+    let read =
+        |path: &Path| std::fs::read(path).with_context(|| format!("Couldn't read {:?}", path));
+    let tls = ClientTlsConfig::new()
+        .domain_name("cln")
+        .ca_certificate(Certificate::from_pem(read(ca_cert)?))
+        .identity(Identity::from_pem(read(client_cert)?, read(client_key)?));
+    let channel = Channel::from_shared(endpoint.to_string())
+        .with_context(|| format!("Invalid gRPC endpoint {}", endpoint))?
+        .tls_config(tls)
+        .context("Invalid TLS configuration")?
+        .connect()
+        .await
+        .with_context(|| format!("Couldn't connect to cln-grpc on {}", endpoint))?;
+    Ok(NodeClient::new(channel))
+}
+
+/// Node ids and channel ids are hex encoded in the JSON-RPC and raw bytes in gRPC
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 == 1 {
+        return Err(anyhow!("{} has an odd number of hex digits", hex));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| anyhow!("{} is not hex", hex))
+        })
+        .collect()
+}
+fn state(state: GrpcState) -> ListpeersPeersChannelsState {
+    use ListpeersPeersChannelsState::*;
+    match state {
+        GrpcState::Openingd => OPENINGD,
+        GrpcState::ChanneldAwaitingLockin => CHANNELD_AWAITING_LOCKIN,
+        GrpcState::ChanneldNormal => CHANNELD_NORMAL,
+        GrpcState::ChanneldShuttingDown => CHANNELD_SHUTTING_DOWN,
+        GrpcState::ClosingdSigexchange => CLOSINGD_SIGEXCHANGE,
+        GrpcState::ClosingdComplete => CLOSINGD_COMPLETE,
+        GrpcState::AwaitingUnilateral => AWAITING_UNILATERAL,
+        GrpcState::FundingSpendSeen => FUNDING_SPEND_SEEN,
+        GrpcState::Onchain => ONCHAIN,
+        GrpcState::DualopendOpenInit => DUALOPEND_OPEN_INIT,
+        GrpcState::DualopendAwaitingLockin => DUALOPEND_AWAITING_LOCKIN,
+    }
+}
+
+pub async fn get_info(node: &mut NodeClient<Channel>) -> Result<NodeInfo> {
+    let info = node.getinfo(proto::GetinfoRequest {}).await?.into_inner();
+    Ok(NodeInfo {
+        id: to_hex(&info.id),
+        network: info.network,
+        blockheight: info.blockheight,
+    })
+}
+pub async fn get_peers(node: &mut NodeClient<Channel>) -> Result<Vec<Peer>> {
+    let response = node
+        .list_peers(proto::ListpeersRequest {
+            id: None,
+            level: None,
+        })
+        .await?
+        .into_inner();
+    Ok(response
+        .peers
+        .into_iter()
+        .map(|peer| Peer {
+            id: to_hex(&peer.id),
+            connected: peer.connected,
+            channels: peer
+                .channels
+                .into_iter()
+                .map(|channel| PeerChannel {
+                    state: state(channel.state()),
+                    short_channel_id: channel.short_channel_id,
+                    channel_id: channel.channel_id.as_deref().map(to_hex),
+                    fee: channel.fee_proportional_millionths,
+                    private: channel.private.unwrap_or(false),
+                    to_us_msat: channel.to_us_msat.map(|a| a.msat).unwrap_or(0),
+                    total_msat: channel.total_msat.map(|a| a.msat).unwrap_or(0),
+                })
+                .collect(),
+        })
+        .collect())
+}
+/// Returns when each settled forward out of a channel was received and the fee it earned
+pub async fn get_forward_fees(
+    short_channel_id: String,
+    node: &mut NodeClient<Channel>,
+) -> Result<Vec<(f64, u64)>> {
+    let response = node
+        .list_forwards(proto::ListforwardsRequest {
+            status: Some(ListforwardsStatus::Settled as i32),
+            in_channel: None,
+            out_channel: Some(short_channel_id),
+        })
+        .await?
+        .into_inner();
+    Ok(response
+        .forwards
+        .into_iter()
+        .map(|forward| {
+            (
+                forward.received_time,
+                forward.fee_msat.map(|fee| fee.msat).unwrap_or(0),
+            )
+        })
+        .collect())
+}
+pub async fn get_gossip_channels(
+    destination: Option<&str>,
+    node: &mut NodeClient<Channel>,
+) -> Result<Vec<GossipChannel>> {
+    let response = node
+        .list_channels(proto::ListchannelsRequest {
+            short_channel_id: None,
+            source: None,
+            destination: destination.map(from_hex).transpose()?,
+        })
+        .await?
+        .into_inner();
+    Ok(response
+        .channels
+        .into_iter()
+        .map(|channel| GossipChannel {
+            short_channel_id: channel.short_channel_id,
+            source: to_hex(&channel.source),
+            destination: to_hex(&channel.destination),
+            fee_per_millionth: channel.fee_per_millionth,
+            active: channel.active,
+        })
+        .collect())
+}
+pub async fn get_node_aliases(node: &mut NodeClient<Channel>) -> Result<HashMap<String, String>> {
+    let response = node
+        .list_nodes(proto::ListnodesRequest { id: None })
+        .await?
+        .into_inner();
+    Ok(response
+        .nodes
+        .into_iter()
+        .filter_map(|node| Some((to_hex(&node.nodeid), node.alias?)))
+        .collect())
+}
+pub async fn set_channel_fee(
+    channel: &str,
+    fee: u32,
+    node: &mut NodeClient<Channel>,
+) -> Result<()> {
+    node.set_channel(proto::SetchannelRequest {
+        id: channel.into(),
+        feebase: None,
+        feeppm: Some(fee),
+        htlcmin: None,
+        htlcmax: None,
+        enforcedelay: None,
+    })
+    .await?;
+    Ok(())
+}
+
+/// A node that serves cln-grpc over mTLS with certificates like the ones CLN creates
+#[cfg(test)]
+pub mod stub {
+    use super::*;
+    use crate::rpc::Client;
+    use proto::node_server::{Node, NodeServer};
+    use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::{Server, ServerTlsConfig};
+    use tonic::{Request, Response, Status};
+
+    pub const SHORT_CHANNEL_ID: &str = "750000x11x0";
+    pub fn node_id() -> String {
+        format!("02{}", "aa".repeat(32))
+    }
+    pub fn peer_id() -> String {
+        format!("03{}", "11".repeat(32))
+    }
+    pub fn channel_id() -> String {
+        "ab".repeat(32)
+    }
+
+    pub fn certificate_authority() -> rcgen::Certificate {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "cln Root CA");
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+    /// Returns the certificate signed by the CA and its key
+    fn issue(
+        name: &str,
+        usage: ExtendedKeyUsagePurpose,
+        ca: &rcgen::Certificate,
+    ) -> (String, String) {
+        let mut params = CertificateParams::new(vec![name.into(), "localhost".into()]);
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![usage];
+        let certificate = rcgen::Certificate::from_params(params).unwrap();
+        (
+            certificate.serialize_pem_with_signer(ca).unwrap(),
+            certificate.serialize_private_key_pem(),
+        )
+    }
+    pub fn client_certificate(ca: &rcgen::Certificate) -> (String, String) {
+        issue("cln grpc client", ExtendedKeyUsagePurpose::ClientAuth, ca)
+    }
+
+    /// Answers like a node with one channel to one peer. The fees that are set are recorded and the
+    /// last one is listed as the fee of the channel.
+    #[derive(Default)]
+    struct Stub {
+        fees: Arc<Mutex<Vec<(String, u32)>>>,
+    }
+    impl Stub {
+        fn info(&self, _: proto::GetinfoRequest) -> Result<proto::GetinfoResponse, &'static str> {
+            Ok(proto::GetinfoResponse {
+                id: from_hex(&node_id()).unwrap(),
+                alias: "stub".into(),
+                blockheight: 760000,
+                network: "regtest".into(),
+                ..Default::default()
+            })
+        }
+        fn peers(
+            &self,
+            _: proto::ListpeersRequest,
+        ) -> Result<proto::ListpeersResponse, &'static str> {
+            let fee = self
+                .fees
+                .lock()
+                .unwrap()
+                .last()
+                .map(|(_, fee)| *fee)
+                .unwrap_or(2000);
+            Ok(proto::ListpeersResponse {
+                peers: vec![proto::ListpeersPeers {
+                    id: from_hex(&peer_id()).unwrap(),
+                    connected: true,
+                    channels: vec![proto::ListpeersPeersChannels {
+                        state: GrpcState::ChanneldNormal as i32,
+                        short_channel_id: Some(SHORT_CHANNEL_ID.into()),
+                        channel_id: Some(from_hex(&channel_id()).unwrap()),
+                        to_us_msat: Some(proto::Amount { msat: 250_000 }),
+                        total_msat: Some(proto::Amount { msat: 1_000_000 }),
+                        fee_base_msat: Some(proto::Amount { msat: 1000 }),
+                        fee_proportional_millionths: Some(fee),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+            })
+        }
+        fn channels(
+            &self,
+            request: proto::ListchannelsRequest,
+        ) -> Result<proto::ListchannelsResponse, &'static str> {
+            let channel = |source: String, destination: String, fee| proto::ListchannelsChannels {
+                source: from_hex(&source).unwrap(),
+                destination: from_hex(&destination).unwrap(),
+                short_channel_id: SHORT_CHANNEL_ID.into(),
+                active: true,
+                base_fee_millisatoshi: 1000,
+                fee_per_millionth: fee,
+                ..Default::default()
+            };
+            let channels = vec![
+                channel(node_id(), peer_id(), 2000),
+                channel(peer_id(), node_id(), 0),
+            ];
+            Ok(proto::ListchannelsResponse {
+                channels: channels
+                    .into_iter()
+                    .filter(|channel| match &request.destination {
+                        Some(destination) => channel.destination == *destination,
+                        None => true,
+                    })
+                    .collect(),
+            })
+        }
+        fn forwards(
+            &self,
+            request: proto::ListforwardsRequest,
+        ) -> Result<proto::ListforwardsResponse, &'static str> {
+            if request.status != Some(ListforwardsStatus::Settled as i32) {
+                return Err("Only settled forwards are stubbed");
+            }
+            let forward = |received_time, fee| proto::ListforwardsForwards {
+                in_channel: "750000x12x0".into(),
+                in_msat: Some(proto::Amount {
+                    msat: 1_000_000 + fee,
+                }),
+                received_time,
+                out_channel: request.out_channel.clone(),
+                fee_msat: Some(proto::Amount { msat: fee }),
+                out_msat: Some(proto::Amount { msat: 1_000_000 }),
+                ..Default::default()
+            };
+            Ok(proto::ListforwardsResponse {
+                forwards: vec![forward(1000.5, 2000), forward(2000.5, 3000)],
+            })
+        }
+        fn nodes(
+            &self,
+            _: proto::ListnodesRequest,
+        ) -> Result<proto::ListnodesResponse, &'static str> {
+            Ok(proto::ListnodesResponse {
+                nodes: vec![
+                    proto::ListnodesNodes {
+                        nodeid: from_hex(&peer_id()).unwrap(),
+                        alias: Some("peer".into()),
+                        ..Default::default()
+                    },
+                    proto::ListnodesNodes {
+                        nodeid: from_hex(&node_id()).unwrap(),
+                        alias: None,
+                        ..Default::default()
+                    },
+                ],
+            })
+        }
+        fn set_fee(
+            &self,
+            request: proto::SetchannelRequest,
+        ) -> Result<proto::SetchannelResponse, &'static str> {
+            let fee = request.feeppm.ok_or("Only the ppm fee is stubbed")?;
+            self.fees.lock().unwrap().push((request.id, fee));
+            Ok(proto::SetchannelResponse::default())
+        }
+    }
+    /// Implements the calls that the feeder uses with the handlers of the stub and lets the other
+    /// calls of the service fail. Handlers fail with the reason why the arguments aren't stubbed.
+    macro_rules! node_service {
+        (
+            served { $($served:ident($served_request:ident) -> $served_response:ident => $handler:ident,)* }
+            unimplemented { $($name:ident($request:ident) -> $response:ident,)* }
+        ) => {
+            #[tonic::async_trait]
+            impl Node for Stub {
+                $(
+                    async fn $served(
+                        &self,
+                        request: Request<proto::$served_request>,
+                    ) -> Result<Response<proto::$served_response>, Status> {
+                        self.$handler(request.into_inner())
+                            .map(Response::new)
+                            .map_err(Status::invalid_argument)
+                    }
+                )*
+                $(
+                    async fn $name(
+                        &self,
+                        _: Request<proto::$request>,
+                    ) -> Result<Response<proto::$response>, Status> {
+                        Err(Status::unimplemented(stringify!($name)))
+                    }
+                )*
+            }
+        };
+    }
+    node_service! {
+        served {
+            getinfo(GetinfoRequest) -> GetinfoResponse => info,
+            list_peers(ListpeersRequest) -> ListpeersResponse => peers,
+            list_channels(ListchannelsRequest) -> ListchannelsResponse => channels,
+            list_forwards(ListforwardsRequest) -> ListforwardsResponse => forwards,
+            list_nodes(ListnodesRequest) -> ListnodesResponse => nodes,
+            set_channel(SetchannelRequest) -> SetchannelResponse => set_fee,
+        }
+        unimplemented {
+            list_funds(ListfundsRequest) -> ListfundsResponse,
+            send_pay(SendpayRequest) -> SendpayResponse,
+            add_gossip(AddgossipRequest) -> AddgossipResponse,
+            auto_clean_invoice(AutocleaninvoiceRequest) -> AutocleaninvoiceResponse,
+            check_message(CheckmessageRequest) -> CheckmessageResponse,
+            close(CloseRequest) -> CloseResponse,
+            connect_peer(ConnectRequest) -> ConnectResponse,
+            create_invoice(CreateinvoiceRequest) -> CreateinvoiceResponse,
+            datastore(DatastoreRequest) -> DatastoreResponse,
+            create_onion(CreateonionRequest) -> CreateonionResponse,
+            del_datastore(DeldatastoreRequest) -> DeldatastoreResponse,
+            del_expired_invoice(DelexpiredinvoiceRequest) -> DelexpiredinvoiceResponse,
+            del_invoice(DelinvoiceRequest) -> DelinvoiceResponse,
+            invoice(InvoiceRequest) -> InvoiceResponse,
+            list_datastore(ListdatastoreRequest) -> ListdatastoreResponse,
+            list_invoices(ListinvoicesRequest) -> ListinvoicesResponse,
+            send_onion(SendonionRequest) -> SendonionResponse,
+            list_send_pays(ListsendpaysRequest) -> ListsendpaysResponse,
+            list_transactions(ListtransactionsRequest) -> ListtransactionsResponse,
+            pay(PayRequest) -> PayResponse,
+            wait_any_invoice(WaitanyinvoiceRequest) -> WaitanyinvoiceResponse,
+            wait_invoice(WaitinvoiceRequest) -> WaitinvoiceResponse,
+            wait_send_pay(WaitsendpayRequest) -> WaitsendpayResponse,
+            new_addr(NewaddrRequest) -> NewaddrResponse,
+            withdraw(WithdrawRequest) -> WithdrawResponse,
+            key_send(KeysendRequest) -> KeysendResponse,
+            fund_psbt(FundpsbtRequest) -> FundpsbtResponse,
+            send_psbt(SendpsbtRequest) -> SendpsbtResponse,
+            sign_psbt(SignpsbtRequest) -> SignpsbtResponse,
+            utxo_psbt(UtxopsbtRequest) -> UtxopsbtResponse,
+            tx_discard(TxdiscardRequest) -> TxdiscardResponse,
+            tx_prepare(TxprepareRequest) -> TxprepareResponse,
+            tx_send(TxsendRequest) -> TxsendResponse,
+            disconnect(DisconnectRequest) -> DisconnectResponse,
+            feerates(FeeratesRequest) -> FeeratesResponse,
+            fund_channel(FundchannelRequest) -> FundchannelResponse,
+            get_route(GetrouteRequest) -> GetrouteResponse,
+            list_pays(ListpaysRequest) -> ListpaysResponse,
+            ping(PingRequest) -> PingResponse,
+            sign_message(SignmessageRequest) -> SignmessageResponse,
+            stop(StopRequest) -> StopResponse,
+        }
+    }
+
+    /// A running stub. Its certificates are in a temporary directory that is removed on drop.
+    pub struct StubNode {
+        pub endpoint: String,
+        pub certificates: PathBuf,
+        pub fees: Arc<Mutex<Vec<(String, u32)>>>,
+    }
+    impl StubNode {
+        pub async fn start() -> Self {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let certificates = std::env::temp_dir().join(format!(
+                "cln-feeder-stub-{}-{}",
+                std::process::id(),
+                address.port()
+            ));
+            std::fs::create_dir_all(&certificates).unwrap();
+            let ca = certificate_authority();
+            let ca_pem = ca.serialize_pem().unwrap();
+            let (server_pem, server_key) = issue("cln", ExtendedKeyUsagePurpose::ServerAuth, &ca);
+            let (client_pem, client_key) = client_certificate(&ca);
+            for (name, pem) in [
+                ("ca.pem", &ca_pem),
+                ("client.pem", &client_pem),
+                ("client-key.pem", &client_key),
+            ] {
+                std::fs::write(certificates.join(name), pem).unwrap();
+            }
+            let tls = ServerTlsConfig::new()
+                .identity(Identity::from_pem(server_pem, server_key))
+                .client_ca_root(Certificate::from_pem(ca_pem));
+            let stub = Stub::default();
+            let fees = stub.fees.clone();
+            tokio::spawn(
+                Server::builder()
+                    .tls_config(tls)
+                    .unwrap()
+                    .add_service(NodeServer::new(stub))
+                    .serve_with_incoming(TcpListenerStream::new(listener)),
+            );
+            Self {
+                endpoint: format!("https://{}", address),
+                certificates,
+                fees,
+            }
+        }
+        /// Connects with the client certificate of the stub like the feeder does
+        pub async fn connect(&self) -> Client {
+            let path = |name| self.certificates.join(name);
+            let grpc = connect(
+                &self.endpoint,
+                &path("ca.pem"),
+                &path("client.pem"),
+                &path("client-key.pem"),
+            )
+            .await
+            .unwrap();
+            Client::grpc("stub", grpc)
+        }
+    }
+    impl Drop for StubNode {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.certificates);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::stub::*;
+    use crate::rpc::{
+        get_channels_into, get_current_channels, get_current_peers, get_forward_fees, get_info,
+        get_node_aliases, set_channel_fee, Client, Management,
+    };
+    use cln_rpc::primitives::ShortChannelId;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn call_stub_node_over_mtls() {
+        let stub = StubNode::start().await;
+        let mut client = stub.connect().await;
+
+        let info = get_info(&mut client).await.unwrap();
+        assert_eq!(info.id, node_id());
+        assert_eq!(info.network, "regtest");
+        assert_eq!(info.blockheight, 760000);
+
+        let channels = get_current_channels(get_current_peers(&mut client).await.unwrap());
+        let channel = &channels[SHORT_CHANNEL_ID];
+        assert_eq!(channel.channel_id, channel_id());
+        assert_eq!(channel.peer_id, peer_id());
+        assert_eq!(channel.fee, 2000);
+        assert_eq!(channel.management, Management::Managed);
+        assert!(!channel.private);
+        assert_eq!(channel.balance_ratio(), 0.25);
+
+        let short_channel_id = ShortChannelId::from_str(SHORT_CHANNEL_ID).unwrap();
+        assert_eq!(
+            get_forward_fees(short_channel_id, &mut client)
+                .await
+                .unwrap(),
+            vec![(1000.5, 2000), (2000.5, 3000)]
+        );
+
+        let into_peer = get_channels_into(&peer_id(), &mut client).await.unwrap();
+        assert_eq!(into_peer.len(), 1);
+        assert_eq!(into_peer[0].source, node_id());
+        assert_eq!(into_peer[0].fee_per_millionth, 2000);
+        assert!(get_channels_into("not hex", &mut client).await.is_err());
+
+        let aliases = get_node_aliases(&mut client).await.unwrap();
+        assert_eq!(aliases.len(), 1);
+        assert_eq!(aliases[&peer_id()], "peer");
+
+        assert!(set_channel_fee(&mut client, &SHORT_CHANNEL_ID.into(), 1500).await);
+        assert_eq!(
+            *stub.fees.lock().unwrap(),
+            vec![(SHORT_CHANNEL_ID.into(), 1500)]
+        );
+    }
+    #[tokio::test]
+    async fn refuse_client_certificate_of_other_ca() {
+        let stub = StubNode::start().await;
+        let (client_pem, client_key) = client_certificate(&certificate_authority());
+        let (cert, key) = (
+            stub.certificates.join("other.pem"),
+            stub.certificates.join("other-key.pem"),
+        );
+        std::fs::write(&cert, client_pem).unwrap();
+        std::fs::write(&key, client_key).unwrap();
+        // The server may only reject the certificate after the handshake of the client is done
+        let refused = match super::connect(
+            &stub.endpoint,
+            &stub.certificates.join("ca.pem"),
+            &cert,
+            &key,
+        )
+        .await
+        {
+            Ok(grpc) => get_info(&mut Client::grpc("stub", grpc)).await.is_err(),
+            Err(_) => true,
+        };
+        assert!(refused);
+    }
+}
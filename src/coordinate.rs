@@ -0,0 +1,147 @@
+use crate::db::{
+    query_last_peer_values, store_current_values, store_decision, store_peer_values, Decision,
+    Decisions,
+};
+use crate::gossip::bound_by_competitors;
+use crate::metrics::{CHANNEL_EPOCH_FORWARDS, CHANNEL_EPOCH_REVENUE, FEE_CHANGES};
+use crate::rpc::{set_channel_fee, Client, CurrentChannel};
+use crate::uptime::peer_is_flaky;
+use crate::{Cli, Epoch, NewFees};
+use anyhow::Result;
+use chrono::Utc;
+use log::{debug, error, info, trace};
+use rusqlite::Connection;
+
+/// A managed channel whose fee is coordinated with the other channels to its peer
+pub struct CoordinatedChannel {
+    pub id: String,
+    pub channel: CurrentChannel,
+    pub current_fee: u32,
+}
+
+/// Determines one fee for all channels to a peer from the revenue they earned together and
+/// applies it to each of them. The values are stored for the peer and for each channel.
+pub async fn iterate_peer(
+    cli: &Cli,
+    peer_id: &String,
+    channels: Vec<CoordinatedChannel>,
+    node_id: Option<&String>,
+    client: &mut Client,
+    db: &mut Connection,
+    decisions: &Decisions,
+) -> Result<()> {
+    let last_values = query_last_peer_values(peer_id, cli.epochs, db);
+    let now = Utc::now().timestamp();
+    // All channels share the offline intervals of the peer
+    let epoch = Epoch::current(cli, &channels[0].channel.channel_id, &last_values, now, db);
+    if !last_values.is_empty() && epoch.end > now {
+        trace!(
+            "{}: Skipped peer iteration because current epoch is still ongoing",
+            peer_id
+        );
+        return Ok(());
+    }
+
+    // Without the forwards of every channel the revenue of the peer is unknown
+    let mut counted = vec![];
+    for coordinated in channels {
+        let forwards = match epoch.forwards(cli, &coordinated.id, now, client).await {
+            Ok(forwards) => forwards,
+            Err(e) => {
+                error!(
+                    "{}: Skipped peer iteration because the forwards of {} are unknown: {:#}",
+                    peer_id, coordinated.id, e
+                );
+                return Ok(());
+            }
+        };
+        CHANNEL_EPOCH_REVENUE
+            .with_label_values(&[&cli.node_name, &coordinated.id])
+            .set(forwards.revenue.try_into().unwrap_or(i64::MAX));
+        CHANNEL_EPOCH_FORWARDS
+            .with_label_values(&[&cli.node_name, &coordinated.id])
+            .set(forwards.count.try_into().unwrap_or(i64::MAX));
+        counted.push((coordinated, forwards.revenue));
+    }
+    let current_revenue = counted
+        .iter()
+        .fold(0u64, |sum, (_, revenue)| sum.saturating_add(*revenue));
+    // Senders route through the cheapest of parallel channels, so its fee earned the revenue
+    let current_fee = counted
+        .iter()
+        .map(|(coordinated, _)| coordinated.current_fee)
+        .min()
+        .unwrap_or(0);
+    debug!(
+        "{}: Current peer[fee: {}, revenue: {}, channels: {}, last_updated: {}]",
+        peer_id,
+        current_fee,
+        current_revenue,
+        counted.len(),
+        epoch.start
+    );
+
+    let mut values: Vec<(u32, u64)> = last_values
+        .iter()
+        .map(|(_, fee, revenue)| (*fee, *revenue))
+        .collect();
+    values.insert(0, (current_fee, current_revenue));
+    let peer_is_flaky = peer_is_flaky(
+        peer_id,
+        cli.epochs * cli.epoch_length,
+        cli.flaky_threshold,
+        db,
+    );
+    let mut new_fees = NewFees::new(&values, cli.adjustment_divisor, peer_is_flaky, peer_id);
+    let proposed_fee = new_fees.as_mut().map(|new_fees| new_fees.determine());
+    let (new_fee, bounded) = match proposed_fee {
+        Some(proposed_fee) => {
+            let (new_fee, bounded) =
+                bound_by_competitors(cli, proposed_fee, peer_id, node_id, client).await;
+            (Some(new_fee), bounded)
+        }
+        None => (None, None),
+    };
+    for (coordinated, revenue) in counted {
+        let mut decision = Decision {
+            channel_id: coordinated.channel.channel_id.clone(),
+            short_channel_id: coordinated.id.clone(),
+            time: now,
+            aggregates: None,
+            reason: String::from("No last values of peer"),
+            proposed_fee: None,
+            applied_fee: None,
+            success: None,
+        };
+        if let (Some(new_fees), Some(proposed_fee), Some(new_fee)) =
+            (&new_fees, proposed_fee, new_fee)
+        {
+            info!(
+                "{}: New coordinated fee {} -> {} ppm",
+                coordinated.id, coordinated.current_fee, new_fee
+            );
+            let success = set_channel_fee(client, &coordinated.id, new_fee).await;
+            if success && new_fee != coordinated.current_fee {
+                FEE_CHANGES.with_label_values(&[&cli.node_name]).inc();
+            }
+            decision.aggregates = Some(new_fees.aggregates());
+            decision.reason = format!("Coordinated with peer: {}", new_fees.reason());
+            if let Some(bounded) = &bounded {
+                decision.reason = format!("{}, {}", decision.reason, bounded);
+            }
+            decision.proposed_fee = Some(proposed_fee);
+            decision.applied_fee = Some(new_fee);
+            decision.success = Some(success);
+        }
+        store_decision(db, &decision, decisions);
+        store_current_values(
+            db,
+            &coordinated.channel.channel_id,
+            coordinated.id,
+            coordinated.current_fee,
+            revenue,
+        );
+    }
+    store_peer_values(db, peer_id, current_fee, current_revenue);
+    Ok(())
+}
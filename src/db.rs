@@ -1,44 +1,118 @@
+use anyhow::{bail, Result};
 use chrono::Utc;
 use log::{debug, trace};
-use rusqlite::Connection;
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+use tokio::sync::broadcast;
 
-pub fn store_current_values(db: &mut Connection, id: String, fee: u32, revenue: u32) {
-    let now = Utc::now().timestamp();
+/// Every stored decision of a node is sent to the subscribers of its sender
+pub type Decisions = broadcast::Sender<Decision>;
+
+pub fn store_current_values(
+    db: &mut Connection,
+    channel_id: &String,
+    id: String,
+    fee: u32,
+    revenue: u64,
+) {
+    store_values(db, channel_id, id, fee, revenue, Utc::now().timestamp())
+}
+/// Stores the values of a channel at the end of an epoch. The epoch is marked as externally
+/// modified if an external fee change was stored since the end of the previous epoch.
+pub fn store_values(
+    db: &mut Connection,
+    channel_id: &String,
+    id: String,
+    fee: u32,
+    revenue: u64,
+    time: i64,
+) {
     db.execute(
-        "INSERT OR REPLACE INTO channels (short_channel_id, last_fee, last_revenue, last_updated)\
-                     VALUES (?1, ?2, ?3, ?4)",
-        (id.clone(), fee, revenue, now),
+        "INSERT OR REPLACE INTO channels \
+            (channel_id, short_channel_id, last_fee, last_revenue, last_updated, \
+            externally_modified) \
+            VALUES (?1, ?2, ?3, ?4, ?5, EXISTS(SELECT 1 FROM external_changes \
+                WHERE channel_id IS ?1 AND time <= ?5 AND time > IFNULL(( \
+                    SELECT MAX(last_updated) FROM channels \
+                    WHERE channel_id IS ?1 AND last_updated < ?5), 0)))",
+        (channel_id, id.clone(), fee, revenue, time),
     )
     .expect("Couldn't store current values");
     debug!(
-        "{}: Stored [fee: {} msats, revenue: {} msats, time: {}]",
-        id, fee, revenue, now
+        "{}: Stored [fee: {} ppm, revenue: {} msats, time: {}]",
+        id, fee, revenue, time
     );
 }
-pub fn create_table(db: &mut Connection) {
-    db.execute(
-        "CREATE TABLE IF NOT EXISTS channels \
-    (short_channel_id NON NULL, \
-    last_fee NON NULL, \
-    last_revenue NON NULL, \
-    last_updated NON NULL, \
-    PRIMARY KEY (short_channel_id, last_updated))",
-        (),
+/// Moves history that is still stored under the short channel id of a channel to its channel id.
+/// Databases from before history was keyed on the channel id use the short channel id as a
+/// placeholder until the channel is seen on the node.
+pub fn link_channel_id(db: &mut Connection, channel_id: &String, id: &String) {
+    let linked = db
+        .execute(
+            "UPDATE channels SET channel_id = ?1 WHERE channel_id IS ?2",
+            (channel_id, id),
+        )
+        .expect("Couldn't link channel id")
+        + db.execute(
+            "UPDATE offline_intervals SET channel_id = ?1 WHERE channel_id IS ?2",
+            (channel_id, id),
+        )
+        .expect("Couldn't link channel id");
+    if linked > 0 {
+        debug!(
+            "{}: Linked {} rows to channel id {}",
+            id, linked, channel_id
+        );
+    }
+}
+/// Returns whether values of any channel were stored
+pub fn query_has_history(db: &mut Connection) -> bool {
+    db.query_row("SELECT EXISTS(SELECT 1 FROM channels)", [], |row| {
+        row.get(0)
+    })
+    .expect("Couldn't query history")
+}
+pub fn query_epoch_count(channel_id: &String, db: &mut Connection) -> u32 {
+    db.query_row(
+        "SELECT COUNT(*) FROM channels WHERE channel_id IS ?1",
+        [channel_id],
+        |row| row.get(0),
+    )
+    .expect("Couldn't count epochs")
+}
+/// Returns whether a decision whose reason starts with `reason` was stored for the channel and
+/// its fee was set successfully
+pub fn query_has_applied_decision(channel_id: &String, reason: &str, db: &mut Connection) -> bool {
+    db.query_row(
+        "SELECT EXISTS (SELECT 1 FROM decisions \
+            WHERE channel_id IS ?1 AND substr(reason, 1, length(?2)) IS ?2 AND success IS 1)",
+        (channel_id, reason),
+        |row| row.get(0),
     )
-    .expect("Couldn't create database table");
-    trace!("Created database table");
+    .expect("Couldn't query decisions")
+}
+/// Returns the short channel id under which the last values of the channel were stored.
+pub fn query_last_short_channel_id(channel_id: &String, db: &mut Connection) -> Option<String> {
+    db.query_row(
+        "SELECT short_channel_id FROM channels WHERE channel_id IS ?1 \
+            ORDER BY last_updated DESC LIMIT 1",
+        [channel_id],
+        |row| row.get(0),
+    )
+    .optional()
+    .expect("Couldn't query last short channel id")
 }
 pub fn query_last_channel_values(
-    short_channel_id: &String,
+    channel_id: &String,
     count: u32,
     db: &mut Connection,
-) -> Vec<(i64, u32, u32)> {
+) -> Vec<(i64, u32, u64)> {
     db.prepare(
-        "SELECT short_channel_id, last_fee, last_revenue, last_updated FROM channels \
-            WHERE short_channel_id IS ?1 ORDER BY last_updated DESC LIMIT ?2",
+        "SELECT channel_id, last_fee, last_revenue, last_updated FROM channels \
+            WHERE channel_id IS ?1 ORDER BY last_updated DESC LIMIT ?2",
     )
     .expect("Preparing query for last values failed")
-    .query([short_channel_id, &count.to_string()])
+    .query([channel_id, &count.to_string()])
     .expect("Couldn't bind parameters to query")
     .mapped(|row| {
         Ok((
@@ -50,3 +124,608 @@ pub fn query_last_channel_values(
     .map(|row| row.unwrap())
     .collect()
 }
+/// The values that were stored for a channel at the end of an epoch
+#[derive(Serialize)]
+pub struct ChannelValues {
+    pub channel_id: String,
+    pub short_channel_id: String,
+    pub fee: u32,
+    pub revenue: u64,
+    pub time: i64,
+}
+/// Returns the history of all channels ordered by time. It can be limited to one channel by its
+/// channel id or short channel id and to the values stored since a timestamp.
+pub fn query_history(
+    channel_id: Option<&String>,
+    since: Option<i64>,
+    db: &mut Connection,
+) -> Vec<ChannelValues> {
+    db.prepare(
+        "SELECT channel_id, short_channel_id, last_fee, last_revenue, last_updated FROM channels \
+            WHERE (?1 IS NULL OR channel_id IS ?1 OR short_channel_id IS ?1) \
+            AND last_updated >= IFNULL(?2, 0) \
+            ORDER BY last_updated, channel_id",
+    )
+    .expect("Preparing query for history failed")
+    .query_map((channel_id, since), |row| {
+        Ok(ChannelValues {
+            channel_id: row.get("channel_id")?,
+            short_channel_id: row.get("short_channel_id")?,
+            fee: row.get("last_fee")?,
+            revenue: row.get("last_revenue")?,
+            time: row.get("last_updated")?,
+        })
+    })
+    .expect("Couldn't query history")
+    .map(|row| row.unwrap())
+    .collect()
+}
+
+pub fn store_offline_interval(db: &mut Connection, channel_id: &String, fee_before: Option<u32>) {
+    let now = Utc::now().timestamp();
+    db.execute(
+        "INSERT OR REPLACE INTO offline_intervals (channel_id, went_offline, fee_before) \
+                     VALUES (?1, ?2, ?3)",
+        (channel_id, now, fee_before),
+    )
+    .expect("Couldn't store offline interval");
+    debug!(
+        "{}: Stored start of offline interval [time: {}]",
+        channel_id, now
+    );
+}
+/// Returns the start and the fee before the channel went offline of the interval that is
+/// still open for the channel.
+pub fn query_open_offline_interval(
+    channel_id: &String,
+    db: &mut Connection,
+) -> Option<(i64, Option<u32>)> {
+    db.query_row(
+        "SELECT went_offline, fee_before FROM offline_intervals \
+            WHERE channel_id IS ?1 AND came_online IS NULL",
+        [channel_id],
+        |row| Ok((row.get("went_offline")?, row.get("fee_before")?)),
+    )
+    .optional()
+    .expect("Couldn't query open offline interval")
+}
+pub fn close_offline_interval(db: &mut Connection, channel_id: &String) {
+    let now = Utc::now().timestamp();
+    db.execute(
+        "UPDATE offline_intervals SET came_online = ?1 \
+            WHERE channel_id IS ?2 AND came_online IS NULL",
+        (now, channel_id),
+    )
+    .expect("Couldn't close offline interval");
+    debug!(
+        "{}: Stored end of offline interval [time: {}]",
+        channel_id, now
+    );
+}
+/// Sums up the seconds the channel was offline since the given timestamp.
+pub fn query_offline_seconds_since(channel_id: &String, since: i64, db: &mut Connection) -> i64 {
+    let now = Utc::now().timestamp();
+    db.query_row(
+        "SELECT SUM(MIN(IFNULL(came_online, ?2), ?2) - MAX(went_offline, ?1)) \
+            FROM offline_intervals \
+            WHERE channel_id IS ?3 AND IFNULL(came_online, ?2) > ?1",
+        (since, now, channel_id),
+        |row| row.get::<_, Option<i64>>(0),
+    )
+    .expect("Couldn't query offline intervals")
+    .unwrap_or(0)
+}
+pub fn store_peer_observation(db: &mut Connection, peer_id: &String, connected: bool) {
+    let now = Utc::now().timestamp();
+    db.execute(
+        "INSERT OR REPLACE INTO peer_uptime (peer_id, observed, connected) VALUES (?1, ?2, ?3)",
+        (peer_id, now, connected),
+    )
+    .expect("Couldn't store peer observation");
+    trace!(
+        "{}: Stored peer observation [connected: {}, time: {}]",
+        peer_id,
+        connected,
+        now
+    );
+}
+/// Removes the peer observations that were made before the given timestamp. Returns how many
+/// were removed.
+pub fn remove_peer_observations_before(db: &mut Connection, before: i64) -> usize {
+    db.execute("DELETE FROM peer_uptime WHERE observed < ?1", [before])
+        .expect("Couldn't remove peer observations")
+}
+/// Returns the percentage of observations since the given timestamp in which the peer was
+/// connected or `None` if the peer wasn't observed in that time.
+pub fn query_peer_uptime(peer_id: &String, since: i64, db: &mut Connection) -> Option<f64> {
+    db.query_row(
+        "SELECT AVG(connected) * 100 FROM peer_uptime WHERE peer_id IS ?1 AND observed >= ?2",
+        (peer_id, since),
+        |row| row.get(0),
+    )
+    .expect("Couldn't query peer uptime")
+}
+pub fn query_observed_peers(db: &mut Connection) -> Vec<String> {
+    db.prepare("SELECT DISTINCT peer_id FROM peer_uptime ORDER BY peer_id")
+        .expect("Preparing query for observed peers failed")
+        .query_map([], |row| row.get(0))
+        .expect("Couldn't query observed peers")
+        .map(|row| row.unwrap())
+        .collect()
+}
+
+/// The values of past epochs that a fee was determined from
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Aggregates {
+    pub past_revenue: u64,
+    pub average_revenue: u64,
+    pub present_revenue: u64,
+    pub current_revenue: u64,
+    pub past_fee: u32,
+    pub average_fee: u32,
+    pub present_fee: u32,
+    pub current_fee: u32,
+}
+/// Why the fee of a channel was changed or left alone
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Decision {
+    pub channel_id: String,
+    pub short_channel_id: String,
+    pub time: i64,
+    pub aggregates: Option<Aggregates>,
+    pub reason: String,
+    pub proposed_fee: Option<u32>,
+    pub applied_fee: Option<u32>,
+    /// Whether setting the applied fee on the node succeeded
+    pub success: Option<bool>,
+}
+pub fn store_decision(db: &mut Connection, decision: &Decision, decisions: &Decisions) {
+    let aggregates = decision.aggregates.as_ref();
+    db.execute(
+        "INSERT INTO decisions \
+            (channel_id, short_channel_id, time, \
+            past_revenue, average_revenue, present_revenue, current_revenue, \
+            past_fee, average_fee, present_fee, current_fee, \
+            reason, proposed_fee, applied_fee, success) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+        rusqlite::params![
+            decision.channel_id,
+            decision.short_channel_id,
+            decision.time,
+            aggregates.map(|a| a.past_revenue),
+            aggregates.map(|a| a.average_revenue),
+            aggregates.map(|a| a.present_revenue),
+            aggregates.map(|a| a.current_revenue),
+            aggregates.map(|a| a.past_fee),
+            aggregates.map(|a| a.average_fee),
+            aggregates.map(|a| a.present_fee),
+            aggregates.map(|a| a.current_fee),
+            decision.reason,
+            decision.proposed_fee,
+            decision.applied_fee,
+            decision.success,
+        ],
+    )
+    .expect("Couldn't store decision");
+    debug!(
+        "{}: Stored decision [reason: {}, proposed: {:?}, applied: {:?}, success: {:?}]",
+        decision.short_channel_id,
+        decision.reason,
+        decision.proposed_fee,
+        decision.applied_fee,
+        decision.success
+    );
+    // Sending only fails when nobody is subscribed
+    let _ = decisions.send(decision.clone());
+}
+/// Returns the latest decisions first. They can be limited to one channel by its channel id or
+/// short channel id and to the ones made since a timestamp.
+pub fn query_decisions(
+    channel_id: Option<&String>,
+    since: Option<i64>,
+    count: u32,
+    db: &mut Connection,
+) -> Vec<Decision> {
+    db.prepare(
+        "SELECT * FROM decisions \
+            WHERE (?1 IS NULL OR channel_id IS ?1 OR short_channel_id IS ?1) \
+            AND time >= IFNULL(?2, 0) \
+            ORDER BY time DESC, id DESC LIMIT ?3",
+    )
+    .expect("Preparing query for decisions failed")
+    .query_map((channel_id, since, count), |row| {
+        let aggregates = if let Some(current_fee) = row.get("current_fee")? {
+            Some(Aggregates {
+                past_revenue: row.get("past_revenue")?,
+                average_revenue: row.get("average_revenue")?,
+                present_revenue: row.get("present_revenue")?,
+                current_revenue: row.get("current_revenue")?,
+                past_fee: row.get("past_fee")?,
+                average_fee: row.get("average_fee")?,
+                present_fee: row.get("present_fee")?,
+                current_fee,
+            })
+        } else {
+            None
+        };
+        Ok(Decision {
+            channel_id: row.get("channel_id")?,
+            short_channel_id: row.get("short_channel_id")?,
+            time: row.get("time")?,
+            aggregates,
+            reason: row.get("reason")?,
+            proposed_fee: row.get("proposed_fee")?,
+            applied_fee: row.get("applied_fee")?,
+            success: row.get("success")?,
+        })
+    })
+    .expect("Couldn't query decisions")
+    .map(|row| row.unwrap())
+    .collect()
+}
+
+/// A fee that was set by hand and is left alone by the feeder until it is removed or expires
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Pin {
+    pub channel_id: String,
+    pub short_channel_id: String,
+    pub fee: u32,
+    pub until: Option<i64>,
+    /// When the fee was pinned
+    pub pinned: i64,
+}
+fn pin_from_row(row: &rusqlite::Row) -> rusqlite::Result<Pin> {
+    Ok(Pin {
+        channel_id: row.get("channel_id")?,
+        short_channel_id: row.get("short_channel_id")?,
+        fee: row.get("fee")?,
+        until: row.get("until")?,
+        pinned: row.get("pinned")?,
+    })
+}
+pub fn store_pin(db: &mut Connection, pin: &Pin) {
+    db.execute(
+        "INSERT OR REPLACE INTO pins (channel_id, short_channel_id, fee, until, pinned) \
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+        (
+            &pin.channel_id,
+            &pin.short_channel_id,
+            pin.fee,
+            pin.until,
+            pin.pinned,
+        ),
+    )
+    .expect("Couldn't store pin");
+    debug!(
+        "{}: Stored pin [fee: {}, until: {:?}]",
+        pin.short_channel_id, pin.fee, pin.until
+    );
+}
+/// Removes the pin of a channel by its channel id or short channel id and returns it
+pub fn remove_pin(db: &mut Connection, channel_id: &String) -> Option<Pin> {
+    let pin = db
+        .query_row(
+            "SELECT * FROM pins WHERE channel_id IS ?1 OR short_channel_id IS ?1",
+            [channel_id],
+            pin_from_row,
+        )
+        .optional()
+        .expect("Couldn't query pin")?;
+    db.execute("DELETE FROM pins WHERE channel_id IS ?1", [&pin.channel_id])
+        .expect("Couldn't remove pin");
+    Some(pin)
+}
+/// Removes the pins that expired before `now` and returns them
+pub fn remove_expired_pins(db: &mut Connection, now: i64) -> Vec<Pin> {
+    let expired: Vec<Pin> = db
+        .prepare("SELECT * FROM pins WHERE until < ?1")
+        .expect("Preparing query for expired pins failed")
+        .query_map([now], pin_from_row)
+        .expect("Couldn't query expired pins")
+        .map(|row| row.unwrap())
+        .collect();
+    db.execute("DELETE FROM pins WHERE until < ?1", [now])
+        .expect("Couldn't remove expired pins");
+    expired
+}
+pub fn query_pin(channel_id: &String, db: &mut Connection) -> Option<Pin> {
+    db.query_row(
+        "SELECT * FROM pins WHERE channel_id IS ?1",
+        [channel_id],
+        pin_from_row,
+    )
+    .optional()
+    .expect("Couldn't query pin")
+}
+
+/// Stores the values of all coordinated channels to a peer at the end of an epoch
+pub fn store_peer_values(db: &mut Connection, peer_id: &String, fee: u32, revenue: u64) {
+    let now = Utc::now().timestamp();
+    db.execute(
+        "INSERT OR REPLACE INTO peers (peer_id, fee, revenue, time) VALUES (?1, ?2, ?3, ?4)",
+        (peer_id, fee, revenue, now),
+    )
+    .expect("Couldn't store peer values");
+    debug!(
+        "{}: Stored peer [fee: {} ppm, revenue: {} msats, time: {}]",
+        peer_id, fee, revenue, now
+    );
+}
+/// Returns the latest values of a peer first
+pub fn query_last_peer_values(
+    peer_id: &String,
+    count: u32,
+    db: &mut Connection,
+) -> Vec<(i64, u32, u64)> {
+    db.prepare(
+        "SELECT time, fee, revenue FROM peers WHERE peer_id IS ?1 ORDER BY time DESC LIMIT ?2",
+    )
+    .expect("Preparing query for peer values failed")
+    .query_map((peer_id, count), |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    })
+    .expect("Couldn't query peer values")
+    .map(|row| row.unwrap())
+    .collect()
+}
+
+/// Returns the fee that the feeder last set successfully on a channel
+pub fn query_last_applied_fee(channel_id: &String, db: &mut Connection) -> Option<u32> {
+    db.query_row(
+        "SELECT applied_fee FROM decisions \
+            WHERE channel_id IS ?1 AND applied_fee IS NOT NULL AND success IS 1 \
+            ORDER BY time DESC, id DESC LIMIT 1",
+        [channel_id],
+        |row| row.get(0),
+    )
+    .optional()
+    .expect("Couldn't query last applied fee")
+}
+pub fn store_external_change(
+    db: &mut Connection,
+    channel_id: &String,
+    expected_fee: u32,
+    fee: u32,
+) {
+    db.execute(
+        "INSERT OR REPLACE INTO external_changes (channel_id, time, expected_fee, fee) \
+            VALUES (?1, ?2, ?3, ?4)",
+        (channel_id, Utc::now().timestamp(), expected_fee, fee),
+    )
+    .expect("Couldn't store external change");
+}
+
+/// Returns the channel ids and last short channel ids of the channels with history that aren't
+/// closed
+pub fn query_open_channels(db: &mut Connection) -> Vec<(String, String)> {
+    db.prepare(
+        "SELECT channel_id, short_channel_id, MAX(last_updated) FROM channels \
+            WHERE channel_id NOT IN (SELECT channel_id FROM closed_channels) \
+            GROUP BY channel_id",
+    )
+    .expect("Preparing query for open channels failed")
+    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+    .expect("Couldn't query open channels")
+    .map(|row| row.unwrap())
+    .collect()
+}
+pub fn store_closed_channel(db: &mut Connection, channel_id: &String, id: &String, time: i64) {
+    db.execute(
+        "INSERT OR IGNORE INTO closed_channels (channel_id, short_channel_id, closed) \
+            VALUES (?1, ?2, ?3)",
+        (channel_id, id, time),
+    )
+    .expect("Couldn't store closed channel");
+}
+
+pub fn query_metadata(key: &str, db: &mut Connection) -> Option<String> {
+    db.query_row("SELECT value FROM metadata WHERE key IS ?1", [key], |row| {
+        row.get(0)
+    })
+    .optional()
+    .expect("Couldn't query metadata")
+}
+pub fn store_metadata(db: &mut Connection, key: &str, value: &str) {
+    db.execute(
+        "INSERT OR REPLACE INTO metadata (key, value) VALUES (?1, ?2)",
+        (key, value),
+    )
+    .expect("Couldn't store metadata");
+}
+/// Ties the database to the node and network it is first used with and fails when it is used
+/// with another one, so that the histories of different nodes never mix.
+pub fn scope_to_node(node_id: &str, network: &str, db: &mut Connection) -> Result<()> {
+    for (key, value) in [("node_id", node_id), ("network", network)] {
+        match query_metadata(key, db) {
+            Some(stored) if stored != value => bail!(
+                "The database belongs to {} {} but the node has {} {}. \
+                Use another data dir for this node",
+                key,
+                stored,
+                key,
+                value
+            ),
+            Some(_) => {}
+            None => {
+                debug!("Scoping database to {} {}", key, value);
+                store_metadata(db, key, value);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::migrate;
+    #[test]
+    fn migrate_legacy_history_to_channel_id() {
+        let mut db = Connection::open_in_memory().unwrap();
+        db.execute(
+            "CREATE TABLE channels \
+            (short_channel_id NON NULL, \
+            last_fee NON NULL, \
+            last_revenue NON NULL, \
+            last_updated NON NULL, \
+            PRIMARY KEY (short_channel_id, last_updated))",
+            (),
+        )
+        .unwrap();
+        db.execute(
+            "INSERT INTO channels VALUES ('1x2x3', 100, 2000, 1660000000)",
+            (),
+        )
+        .unwrap();
+        migrate(&mut db);
+        let id = String::from("1x2x3");
+        let channel_id = "ab".repeat(32);
+        assert_eq!(
+            query_last_channel_values(&id, 6, &mut db),
+            vec![(1660000000, 100, 2000)]
+        );
+        link_channel_id(&mut db, &channel_id, &id);
+        assert!(query_last_channel_values(&id, 6, &mut db).is_empty());
+        assert_eq!(
+            query_last_channel_values(&channel_id, 6, &mut db),
+            vec![(1660000000, 100, 2000)]
+        );
+        assert_eq!(query_last_short_channel_id(&channel_id, &mut db), Some(id));
+    }
+    #[test]
+    fn store_and_query_decisions() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db);
+        let decisions = broadcast::channel(64).0;
+        let decision = Decision {
+            channel_id: "ab".repeat(32),
+            short_channel_id: String::from("1x2x3"),
+            time: 1660000000,
+            aggregates: Some(Aggregates {
+                past_revenue: 100,
+                average_revenue: 200,
+                present_revenue: 300,
+                current_revenue: 5_000_000_000,
+                past_fee: 10,
+                average_fee: 20,
+                present_fee: 30,
+                current_fee: 40,
+            }),
+            reason: String::from("Revenue is rising, Fee is rising, Increasing fee fast"),
+            proposed_fee: Some(54),
+            applied_fee: Some(54),
+            success: Some(true),
+        };
+        store_decision(&mut db, &decision, &decisions);
+        let without_aggregates = Decision {
+            time: 1660086400,
+            aggregates: None,
+            reason: String::from("No last values"),
+            proposed_fee: None,
+            applied_fee: None,
+            success: None,
+            ..decision.clone()
+        };
+        store_decision(&mut db, &without_aggregates, &decisions);
+        assert_eq!(
+            query_decisions(Some(&decision.channel_id), None, 10, &mut db),
+            vec![without_aggregates, decision.clone()]
+        );
+        assert_eq!(
+            query_decisions(None, Some(1660000001), 10, &mut db).len(),
+            1
+        );
+    }
+    #[test]
+    fn keep_decisions_of_the_same_second() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db);
+        let decisions = broadcast::channel(64).0;
+        let came_online = Decision {
+            channel_id: "ab".repeat(32),
+            short_channel_id: String::from("1x2x3"),
+            time: 1660000000,
+            aggregates: None,
+            reason: String::from("Peer came back online"),
+            proposed_fee: Some(100),
+            applied_fee: Some(100),
+            success: Some(true),
+        };
+        let epoch = Decision {
+            reason: String::from("Revenue is rising, Increasing fee"),
+            proposed_fee: Some(110),
+            applied_fee: Some(110),
+            success: Some(false),
+            ..came_online.clone()
+        };
+        store_decision(&mut db, &came_online, &decisions);
+        store_decision(&mut db, &epoch, &decisions);
+        assert_eq!(
+            query_decisions(Some(&came_online.channel_id), None, 10, &mut db),
+            vec![epoch, came_online.clone()]
+        );
+        assert_eq!(
+            query_last_applied_fee(&came_online.channel_id, &mut db),
+            Some(100)
+        );
+    }
+    #[test]
+    fn expire_and_remove_pins() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db);
+        let pin = Pin {
+            channel_id: "ab".repeat(32),
+            short_channel_id: String::from("1x2x3"),
+            fee: 500,
+            until: Some(1660086400),
+            pinned: 1660000000,
+        };
+        store_pin(&mut db, &pin);
+        let forever = Pin {
+            channel_id: "cd".repeat(32),
+            short_channel_id: String::from("4x5x6"),
+            until: None,
+            ..pin.clone()
+        };
+        store_pin(&mut db, &forever);
+        assert!(remove_expired_pins(&mut db, 1660086400).is_empty());
+        assert_eq!(query_pin(&pin.channel_id, &mut db), Some(pin.clone()));
+        assert_eq!(remove_expired_pins(&mut db, 1660086401), vec![pin.clone()]);
+        assert_eq!(query_pin(&pin.channel_id, &mut db), None);
+        assert_eq!(
+            remove_pin(&mut db, &String::from("4x5x6")),
+            Some(forever.clone())
+        );
+        assert_eq!(query_pin(&forever.channel_id, &mut db), None);
+    }
+    #[test]
+    fn mark_externally_modified_epochs() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db);
+        let channel_id = "ab".repeat(32);
+        let now = Utc::now().timestamp();
+        store_values(&mut db, &channel_id, "1x2x3".into(), 100, 0, now - 20);
+        store_external_change(&mut db, &channel_id, 100, 200);
+        store_values(&mut db, &channel_id, "1x2x3".into(), 200, 0, now + 10);
+        store_values(&mut db, &channel_id, "1x2x3".into(), 200, 0, now + 20);
+        let marked: Vec<bool> = db
+            .prepare("SELECT externally_modified FROM channels ORDER BY last_updated")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(marked, vec![false, true, false]);
+    }
+    #[test]
+    fn refuse_other_node_or_network() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db);
+        let node_id = format!("02{}", "aa".repeat(32));
+        scope_to_node(&node_id, "bitcoin", &mut db).unwrap();
+        scope_to_node(&node_id, "bitcoin", &mut db).unwrap();
+        assert!(scope_to_node(&node_id, "testnet", &mut db).is_err());
+        assert!(scope_to_node(&format!("03{}", "aa".repeat(32)), "bitcoin", &mut db).is_err());
+        assert_eq!(query_metadata("network", &mut db).unwrap(), "bitcoin");
+    }
+}
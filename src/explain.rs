@@ -0,0 +1,139 @@
+use crate::db::{query_decisions, query_last_channel_values, query_pin};
+use crate::filter::exclusion;
+use crate::gossip::get_competitor_fees;
+use crate::report::format_time;
+use crate::rpc::{get_current_channels, get_current_peers, get_node_id, Client};
+use crate::uptime::peer_is_flaky;
+use crate::{Cli, Epoch, NewFees};
+use anyhow::Result;
+use chrono::Utc;
+use rusqlite::Connection;
+
+/// Prints how the next fee of a channel would be calculated from its history and current state.
+/// Nothing is changed on the node or in the database.
+pub async fn explain(
+    cli: &Cli,
+    channel: &String,
+    client: &mut Client,
+    db: &mut Connection,
+) -> Result<()> {
+    let peers = get_current_peers(client).await?;
+    let current_channels = get_current_channels(peers);
+    let (id, current) = if let Some(current) = current_channels
+        .iter()
+        .find(|(id, current)| *id == channel || current.channel_id == *channel)
+    {
+        current
+    } else {
+        println!("Channel {} is not known to the node", channel);
+        return Ok(());
+    };
+    let channel_id = &current.channel_id;
+    println!("Channel {} ({})", id, channel_id);
+    println!(
+        "Peer {} is {}",
+        current.peer_id,
+        if current.connected {
+            "connected"
+        } else {
+            "offline"
+        }
+    );
+    println!("State {} -> {:?}", current.state, current.management);
+    if let Some(excluded) = exclusion(cli, id, current) {
+        println!("Excluded from management: {}", excluded);
+        println!("Excluded channels keep their fee -> No fee would be proposed");
+        return Ok(());
+    }
+    if cli.coordinate_peers {
+        println!("Fee is coordinated across all channels to the peer");
+        println!("The proposal below only considers this channel");
+    }
+
+    let last_values = query_last_channel_values(channel_id, cli.epochs, db);
+    let now = Utc::now().timestamp();
+    let epoch = Epoch::current(cli, channel_id, &last_values, now, db);
+    println!(
+        "Recorded epochs: {}. Current epoch started {} and ends {}",
+        last_values.len(),
+        format_time(epoch.start),
+        format_time(epoch.end)
+    );
+    if epoch.offline_seconds > 0 {
+        println!(
+            "Peer was offline for {} minutes during the current epoch",
+            epoch.offline_seconds / 60
+        );
+    }
+    if let Some(decision) = query_decisions(Some(channel_id), None, 1, db).first() {
+        println!(
+            "Last decision at {}: {}",
+            format_time(decision.time),
+            decision.reason
+        );
+    }
+
+    if let Some(pin) = query_pin(channel_id, db) {
+        println!(
+            "Fee is pinned at {} ppm {}",
+            pin.fee,
+            pin.until
+                .map(|until| format!("until {}", format_time(until)))
+                .unwrap_or_else(|| "indefinitely".into())
+        );
+        println!("Pinned channels keep their fee -> No fee would be proposed");
+        return Ok(());
+    }
+
+    let current_revenue = epoch.forwards(cli, id, now, client).await?.revenue;
+    println!(
+        "Current fee: {} ppm, revenue: {} msats",
+        current.fee, current_revenue
+    );
+    let mut values: Vec<(u32, u64)> = last_values
+        .iter()
+        .map(|(_, fee, revenue)| (*fee, *revenue))
+        .collect();
+    values.insert(0, (current.fee, current_revenue));
+    // The graph is only needed when the fee is bounded by competitors
+    let competitors = if cli.competitor_floor.is_some() || cli.competitor_ceiling.is_some() {
+        Some(get_competitor_fees(&current.peer_id, &get_node_id(client).await?, client).await?)
+    } else {
+        None
+    };
+    if let Some(competitors) = competitors.as_ref().filter(|fees| !fees.is_empty()) {
+        println!(
+            "Competitors into peer: {} channels. 10th/50th/90th percentile: {}/{}/{} ppm",
+            competitors.len(),
+            competitors.percentile(10.0).unwrap(),
+            competitors.percentile(50.0).unwrap(),
+            competitors.percentile(90.0).unwrap()
+        );
+    }
+    let peer_is_flaky = peer_is_flaky(
+        &current.peer_id,
+        cli.epochs * cli.epoch_length,
+        cli.flaky_threshold,
+        db,
+    );
+    if let Some(mut new_fees) = NewFees::new(&values, cli.adjustment_divisor, peer_is_flaky, id) {
+        println!("{}", new_fees);
+        println!("Revenue is {}", new_fees.revenue_trend());
+        println!("Fee is {}", new_fees.fee_trend());
+        let new_fee = new_fees.determine();
+        println!(
+            "Proposed fee: {} -> {} ppm ({})",
+            current.fee,
+            new_fee,
+            new_fees.reason()
+        );
+        if let Some((bounded, reason)) = competitors.and_then(|competitors| {
+            competitors.bound(new_fee, cli.competitor_floor, cli.competitor_ceiling)
+        }) {
+            println!("{} -> {} ppm", reason, bounded);
+        }
+    } else {
+        println!("No last values -> No fee would be proposed");
+    }
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use crate::db::{query_decisions, query_history};
+use chrono::{TimeZone, Utc};
+use clap::ValueEnum;
+use rusqlite::Connection;
+use serde::Serialize;
+use std::io::Write;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Csv,
+    Json,
+}
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Table {
+    /// The fee and revenue of every channel at the end of each epoch
+    History,
+    /// The fee decisions and the reasoning behind them
+    Decisions,
+}
+
+fn rfc3339(timestamp: i64) -> String {
+    Utc.timestamp_opt(timestamp, 0).unwrap().to_rfc3339()
+}
+#[derive(Serialize)]
+struct HistoryRow {
+    time: String,
+    timestamp: i64,
+    channel_id: String,
+    short_channel_id: String,
+    fee: u32,
+    revenue: u64,
+}
+/// Decisions are flattened because CSV can't represent nested values
+#[derive(Serialize)]
+struct DecisionRow {
+    time: String,
+    timestamp: i64,
+    channel_id: String,
+    short_channel_id: String,
+    past_revenue: Option<u64>,
+    average_revenue: Option<u64>,
+    present_revenue: Option<u64>,
+    current_revenue: Option<u64>,
+    past_fee: Option<u32>,
+    average_fee: Option<u32>,
+    present_fee: Option<u32>,
+    current_fee: Option<u32>,
+    reason: String,
+    proposed_fee: Option<u32>,
+    applied_fee: Option<u32>,
+    success: Option<bool>,
+}
+fn write_rows<T: Serialize>(rows: Vec<T>, format: Format, out: impl Write) {
+    match format {
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            for row in rows {
+                writer.serialize(row).expect("Couldn't write CSV row");
+            }
+            writer.flush().expect("Couldn't write CSV");
+        }
+        Format::Json => {
+            serde_json::to_writer_pretty(out, &rows).expect("Couldn't write JSON");
+        }
+    }
+}
+/// Writes a table of the database to `out`. It can be limited to one channel by its channel id or
+/// short channel id and to the rows since a timestamp.
+pub fn export(
+    table: Table,
+    format: Format,
+    channel: Option<&String>,
+    since: Option<i64>,
+    db: &mut Connection,
+    out: impl Write,
+) {
+    match table {
+        Table::History => {
+            let rows = query_history(channel, since, db)
+                .into_iter()
+                .map(|values| HistoryRow {
+                    time: rfc3339(values.time),
+                    timestamp: values.time,
+                    channel_id: values.channel_id,
+                    short_channel_id: values.short_channel_id,
+                    fee: values.fee,
+                    revenue: values.revenue,
+                })
+                .collect();
+            write_rows::<HistoryRow>(rows, format, out)
+        }
+        Table::Decisions => {
+            let mut decisions = query_decisions(channel, since, u32::MAX, db);
+            decisions.reverse();
+            let rows = decisions
+                .into_iter()
+                .map(|decision| {
+                    let aggregates = decision.aggregates.as_ref();
+                    DecisionRow {
+                        time: rfc3339(decision.time),
+                        timestamp: decision.time,
+                        past_revenue: aggregates.map(|a| a.past_revenue),
+                        average_revenue: aggregates.map(|a| a.average_revenue),
+                        present_revenue: aggregates.map(|a| a.present_revenue),
+                        current_revenue: aggregates.map(|a| a.current_revenue),
+                        past_fee: aggregates.map(|a| a.past_fee),
+                        average_fee: aggregates.map(|a| a.average_fee),
+                        present_fee: aggregates.map(|a| a.present_fee),
+                        current_fee: aggregates.map(|a| a.current_fee),
+                        channel_id: decision.channel_id,
+                        short_channel_id: decision.short_channel_id,
+                        reason: decision.reason,
+                        proposed_fee: decision.proposed_fee,
+                        applied_fee: decision.applied_fee,
+                        success: decision.success,
+                    }
+                })
+                .collect();
+            write_rows::<DecisionRow>(rows, format, out)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::store_current_values;
+    use crate::migrations::migrate;
+    #[test]
+    fn export_history_as_csv() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db);
+        let channel_id = "ab".repeat(32);
+        store_current_values(&mut db, &channel_id, "1x2x3".into(), 100, 2000);
+        let mut out = vec![];
+        export(
+            Table::History,
+            Format::Csv,
+            Some(&String::from("1x2x3")),
+            None,
+            &mut db,
+            &mut out,
+        );
+        let csv = String::from_utf8(out).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("time,timestamp,channel_id,short_channel_id,fee,revenue")
+        );
+        assert!(lines
+            .next()
+            .unwrap()
+            .ends_with(&format!(",{},1x2x3,100,2000", channel_id)));
+        assert_eq!(lines.next(), None);
+    }
+}
@@ -0,0 +1,65 @@
+use crate::rpc::CurrentChannel;
+use crate::Cli;
+
+/// Returns why a channel is excluded from management or `None` if its fee is managed. Excluded
+/// channels are still tracked in the history.
+pub fn exclusion(cli: &Cli, id: &String, channel: &CurrentChannel) -> Option<String> {
+    let listed = |ids: &Vec<String>| ids.contains(id) || ids.contains(&channel.channel_id);
+    if !cli.include_channels.is_empty() && !listed(&cli.include_channels) {
+        return Some("Channel is not included".into());
+    }
+    if listed(&cli.exclude_channels) {
+        return Some("Channel is excluded".into());
+    }
+    if !cli.include_peers.is_empty() && !cli.include_peers.contains(&channel.peer_id) {
+        return Some("Peer is not included".into());
+    }
+    if cli.exclude_peers.contains(&channel.peer_id) {
+        return Some("Peer is excluded".into());
+    }
+    if cli.exclude_private && channel.private {
+        return Some("Private channels are excluded".into());
+    }
+    if let Some(min_capacity) = cli.min_capacity {
+        if channel.total_msat / 1000 < min_capacity {
+            return Some(format!("Capacity is below {} sats", min_capacity));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::Management;
+    use clap::Parser;
+    #[test]
+    fn exclude_by_id_peer_and_properties() {
+        let channel = CurrentChannel {
+            channel_id: "ab".repeat(32),
+            fee: 100,
+            peer_id: "02".repeat(33),
+            connected: true,
+            state: String::from("CHANNELD_NORMAL"),
+            management: Management::Managed,
+            private: true,
+            to_us_msat: 500_000_000,
+            total_msat: 1_000_000_000,
+        };
+        let id = String::from("1x2x3");
+        let excluded = |args: &[&str]| {
+            let cli =
+                Cli::parse_from([&["cln-feeder", "--socket", "lightning-rpc"], args].concat());
+            exclusion(&cli, &id, &channel)
+        };
+        assert_eq!(excluded(&[]), None);
+        assert_eq!(excluded(&["--include-channels", "1x2x3,4x5x6"]), None);
+        assert!(excluded(&["--include-channels", "4x5x6"]).is_some());
+        assert!(excluded(&["--exclude-channels", &"ab".repeat(32)]).is_some());
+        assert!(excluded(&["--exclude-peers", &"02".repeat(33)]).is_some());
+        assert!(excluded(&["--include-peers", &"03".repeat(33)]).is_some());
+        assert!(excluded(&["--exclude-private"]).is_some());
+        assert_eq!(excluded(&["--min-capacity", "1000000"]), None);
+        assert!(excluded(&["--min-capacity", "1000001"]).is_some());
+    }
+}
@@ -0,0 +1,176 @@
+use crate::rpc::{get_channels_into, Client};
+use crate::Cli;
+use anyhow::Result;
+use log::{debug, warn};
+use serde::Deserialize;
+
+/// A direction of a channel in the gossip graph as returned by `listchannels`
+#[derive(Clone, Debug, Deserialize)]
+pub struct GossipChannel {
+    pub short_channel_id: String,
+    pub source: String,
+    pub destination: String,
+    pub fee_per_millionth: u32,
+    pub active: bool,
+}
+
+/// The fees that other nodes charge to forward into a peer
+#[derive(Debug)]
+pub struct CompetitorFees {
+    /// Sorted ascending
+    fees: Vec<u32>,
+}
+impl CompetitorFees {
+    /// Collects the fees of the active channels into the peer that don't belong to our node
+    pub fn new(channels: &[GossipChannel], peer_id: &str, node_id: &str) -> Self {
+        let mut fees: Vec<u32> = channels
+            .iter()
+            .filter(|channel| {
+                channel.active && channel.destination == peer_id && channel.source != node_id
+            })
+            .map(|channel| channel.fee_per_millionth)
+            .collect();
+        fees.sort_unstable();
+        Self { fees }
+    }
+    pub fn len(&self) -> usize {
+        self.fees.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.fees.is_empty()
+    }
+    /// The fee below or at which the given percent of competitors charge, using the nearest rank
+    pub fn percentile(&self, percentile: f64) -> Option<u32> {
+        if self.fees.is_empty() {
+            return None;
+        }
+        let rank = (percentile / 100.0 * self.fees.len() as f64).ceil() as usize;
+        Some(self.fees[rank.clamp(1, self.fees.len()) - 1])
+    }
+    /// Keeps a fee between the percentiles of the competitor fees. Returns the bounded fee and
+    /// why it was bounded or `None` if the fee is within the bounds.
+    pub fn bound(
+        &self,
+        fee: u32,
+        floor: Option<f64>,
+        ceiling: Option<f64>,
+    ) -> Option<(u32, String)> {
+        if let Some((percentile, max)) =
+            ceiling.and_then(|ceiling| Some((ceiling, self.percentile(ceiling)?)))
+        {
+            if fee > max {
+                return Some((
+                    max,
+                    format!("Capped at {}th percentile of competitors", percentile),
+                ));
+            }
+        }
+        if let Some((percentile, min)) =
+            floor.and_then(|floor| Some((floor, self.percentile(floor)?)))
+        {
+            if fee < min {
+                return Some((
+                    min,
+                    format!("Raised to {}th percentile of competitors", percentile),
+                ));
+            }
+        }
+        None
+    }
+}
+
+/// Collects the fees that other nodes charge to forward into a peer from the gossip graph
+pub async fn get_competitor_fees(
+    peer_id: &str,
+    node_id: &str,
+    client: &mut Client,
+) -> Result<CompetitorFees> {
+    let channels = get_channels_into(peer_id, client).await?;
+    Ok(CompetitorFees::new(&channels, peer_id, node_id))
+}
+/// Keeps a fee within the configured percentiles of the fees that other nodes charge into the
+/// peer. `node_id` is only known when bounds are configured. Returns the fee and why it was
+/// bounded. The fee stays unbounded if the competitors can't be queried.
+pub async fn bound_by_competitors(
+    cli: &Cli,
+    fee: u32,
+    peer_id: &String,
+    node_id: Option<&String>,
+    client: &mut Client,
+) -> (u32, Option<String>) {
+    let node_id = if let Some(node_id) = node_id {
+        node_id
+    } else {
+        return (fee, None);
+    };
+    let competitors = match get_competitor_fees(peer_id, node_id, client).await {
+        Ok(competitors) => competitors,
+        Err(e) => {
+            warn!(
+                "{}: Leaving fee unbounded because competitors are unknown: {:#}",
+                peer_id, e
+            );
+            return (fee, None);
+        }
+    };
+    debug!(
+        "{}: {} competitors [10th: {:?}, 50th: {:?}, 90th: {:?}]",
+        peer_id,
+        competitors.len(),
+        competitors.percentile(10.0),
+        competitors.percentile(50.0),
+        competitors.percentile(90.0)
+    );
+    match competitors.bound(fee, cli.competitor_floor, cli.competitor_ceiling) {
+        Some((bounded, reason)) => (bounded, Some(reason)),
+        None => (fee, None),
+    }
+}
+/// Parses a percentile between 0 and 100
+pub fn parse_percentile(percentile: &str) -> Result<f64, String> {
+    match percentile.parse::<f64>() {
+        Ok(percentile) if (0.0..=100.0).contains(&percentile) => Ok(percentile),
+        _ => Err(format!(
+            "{} is not a percentile between 0 and 100",
+            percentile
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[derive(Deserialize)]
+    struct Listchannels {
+        channels: Vec<GossipChannel>,
+    }
+    #[test]
+    fn percentiles_and_bounds_from_fixture() {
+        let graph: Listchannels =
+            serde_json::from_str(include_str!("../fixtures/listchannels.json")).unwrap();
+        let peer_id = format!("03{}", "11".repeat(32));
+        let node_id = format!("02{}", "aa".repeat(32));
+        let competitors = CompetitorFees::new(&graph.channels, &peer_id, &node_id);
+        // Our own channel, the inactive one and the ones out of the peer are ignored
+        assert_eq!(competitors.len(), 9);
+        assert_eq!(competitors.percentile(0.0), Some(10));
+        assert_eq!(competitors.percentile(10.0), Some(10));
+        assert_eq!(competitors.percentile(50.0), Some(200));
+        assert_eq!(competitors.percentile(90.0), Some(1000));
+        assert_eq!(competitors.percentile(100.0), Some(1000));
+
+        assert_eq!(competitors.bound(250, Some(25.0), Some(75.0)), None);
+        assert_eq!(
+            competitors.bound(400, Some(25.0), Some(75.0)).unwrap().0,
+            300
+        );
+        assert_eq!(
+            competitors.bound(20, Some(25.0), Some(75.0)).unwrap().0,
+            100
+        );
+        assert_eq!(
+            CompetitorFees::new(&[], &peer_id, &node_id).bound(20, Some(25.0), None),
+            None
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use crate::gossip::{CompetitorFees, GossipChannel};
+use crate::report::format_alias;
+use crate::rpc::{get_gossip_graph, get_node_aliases, get_node_id, Client};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+
+/// Peers with fewer alternative last hops than this are only reached through few nodes
+const FEW_ALTERNATIVES: usize = 5;
+/// Peers with at least this many alternative last hops are reached through many nodes
+const MANY_ALTERNATIVES: usize = 25;
+
+/// A dump of the gossip graph from `listchannels` and optionally `listnodes`, e.g. made with
+/// `jq -s add <(lightning-cli listchannels) <(lightning-cli listnodes)`
+#[derive(Deserialize)]
+pub struct Snapshot {
+    channels: Vec<GossipChannel>,
+    #[serde(default)]
+    nodes: Vec<SnapshotNode>,
+}
+#[derive(Deserialize)]
+struct SnapshotNode {
+    nodeid: String,
+    alias: Option<String>,
+}
+
+/// The nodes of the graph and whom they have active channels with in either direction
+struct Graph<'a> {
+    neighbours: HashMap<&'a str, HashSet<&'a str>>,
+}
+impl<'a> Graph<'a> {
+    fn new(channels: &'a [GossipChannel]) -> Self {
+        let mut neighbours: HashMap<&str, HashSet<&str>> = HashMap::new();
+        for channel in channels.iter().filter(|channel| channel.active) {
+            neighbours
+                .entry(&channel.source)
+                .or_default()
+                .insert(&channel.destination);
+            neighbours
+                .entry(&channel.destination)
+                .or_default()
+                .insert(&channel.source);
+        }
+        Self { neighbours }
+    }
+    fn degree(&self, node: &str) -> usize {
+        self.neighbours
+            .get(node)
+            .map_or(0, |neighbours| neighbours.len())
+    }
+    /// The number of reachable nodes divided by the sum of the hops to reach them
+    fn closeness(&self, node: &str) -> f64 {
+        let mut hops: HashMap<&str, u32> = HashMap::from([(node, 0)]);
+        let mut queue = VecDeque::from([node]);
+        while let Some(current) = queue.pop_front() {
+            let distance = hops[current] + 1;
+            for neighbour in self.neighbours.get(current).into_iter().flatten() {
+                if !hops.contains_key(neighbour) {
+                    hops.insert(neighbour, distance);
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+        let total: u64 = hops.values().map(|hops| u64::from(*hops)).sum();
+        if total == 0 {
+            return 0.0;
+        }
+        (hops.len() - 1) as f64 / total as f64
+    }
+}
+
+/// How much to charge for forwarding into a peer relative to the competitors
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tier {
+    Low,
+    Medium,
+    High,
+}
+impl Tier {
+    /// The percentile of the competitor fees that is suggested for the tier
+    fn percentile(&self) -> f64 {
+        match self {
+            Tier::Low => 25.0,
+            Tier::Medium => 50.0,
+            Tier::High => 75.0,
+        }
+    }
+    /// Peers with few alternatives are in a higher tier. Peers that are more central than our
+    /// node attract more traffic and move up a tier.
+    fn of(alternatives: usize, peer_closeness: f64, node_closeness: f64) -> Self {
+        let tier = if alternatives < FEW_ALTERNATIVES {
+            Tier::High
+        } else if alternatives < MANY_ALTERNATIVES {
+            Tier::Medium
+        } else {
+            Tier::Low
+        };
+        match tier {
+            Tier::Low if peer_closeness > node_closeness => Tier::Medium,
+            Tier::Medium if peer_closeness > node_closeness => Tier::High,
+            tier => tier,
+        }
+    }
+}
+/// The position of one of our channels in the graph
+#[derive(Serialize)]
+pub struct ChannelPosition {
+    pub short_channel_id: String,
+    pub peer_id: String,
+    pub alias: Option<String>,
+    pub peer_channels: usize,
+    pub peer_closeness: f64,
+    /// Other nodes with an active channel into the peer
+    pub alternatives: usize,
+    pub fee: u32,
+    pub tier: Tier,
+    /// The percentile of the competitor fees for the tier
+    pub suggested_fee: Option<u32>,
+}
+#[derive(Serialize)]
+pub struct Analysis {
+    pub node_id: String,
+    pub node_channels: usize,
+    pub node_closeness: f64,
+    pub channels: Vec<ChannelPosition>,
+}
+
+/// Positions each channel of our node in the graph and suggests a fee tier for it
+pub fn analyze(
+    channels: &[GossipChannel],
+    aliases: &HashMap<String, String>,
+    node_id: &str,
+) -> Analysis {
+    let graph = Graph::new(channels);
+    let node_closeness = graph.closeness(node_id);
+    let mut positions: Vec<ChannelPosition> = channels
+        .iter()
+        .filter(|channel| channel.source == node_id)
+        .map(|channel| {
+            let peer_id = &channel.destination;
+            let competitors = CompetitorFees::new(channels, peer_id, node_id);
+            let peer_closeness = graph.closeness(peer_id);
+            let tier = Tier::of(competitors.len(), peer_closeness, node_closeness);
+            ChannelPosition {
+                short_channel_id: channel.short_channel_id.clone(),
+                peer_id: peer_id.clone(),
+                alias: aliases.get(peer_id).cloned(),
+                peer_channels: graph.degree(peer_id),
+                peer_closeness,
+                alternatives: competitors.len(),
+                fee: channel.fee_per_millionth,
+                tier,
+                suggested_fee: competitors.percentile(tier.percentile()),
+            }
+        })
+        .collect();
+    positions.sort_by(|a, b| a.short_channel_id.cmp(&b.short_channel_id));
+    Analysis {
+        node_id: node_id.into(),
+        node_channels: graph.degree(node_id),
+        node_closeness,
+        channels: positions,
+    }
+}
+
+/// Analyzes the graph of a snapshot and prints the suggested fee tiers of our channels
+pub fn analyze_snapshot(path: &PathBuf, node_id: &str, json: bool) -> Result<()> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("Couldn't open snapshot {:?}", path))?;
+    let snapshot: Snapshot = serde_json::from_reader(std::io::BufReader::new(file))
+        .with_context(|| format!("Couldn't read snapshot {:?}", path))?;
+    let aliases = snapshot
+        .nodes
+        .into_iter()
+        .filter_map(|node| Some((node.nodeid, node.alias?)))
+        .collect();
+    print_analysis(analyze(&snapshot.channels, &aliases, node_id), json);
+    Ok(())
+}
+/// Analyzes the current graph of the node and prints the suggested fee tiers of our channels
+pub async fn analyze_live(node_id: Option<&String>, json: bool, client: &mut Client) -> Result<()> {
+    let node_id = match node_id {
+        Some(node_id) => node_id.clone(),
+        None => get_node_id(client).await?,
+    };
+    let channels = get_gossip_graph(client).await?;
+    let aliases = get_node_aliases(client).await?;
+    print_analysis(analyze(&channels, &aliases, &node_id), json);
+    Ok(())
+}
+fn print_analysis(analysis: Analysis, json: bool) {
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&analysis).expect("Couldn't serialize analysis")
+        );
+        return;
+    }
+    println!(
+        "Node {} has {} channels and a closeness of {:.3}",
+        analysis.node_id, analysis.node_channels, analysis.node_closeness
+    );
+    println!(
+        "{:<16} {:<20} {:>8} {:>9} {:>12} {:<6} {:>8} {:>9}",
+        "CHANNEL", "ALIAS", "CHANNELS", "CLOSENESS", "ALTERNATIVES", "TIER", "PPM", "SUGGESTED"
+    );
+    for position in analysis.channels {
+        let alias = format_alias(position.alias.as_ref().unwrap_or(&position.peer_id));
+        println!(
+            "{:<16} {:<20} {:>8} {:>9.3} {:>12} {:<6} {:>8} {:>9}",
+            position.short_channel_id,
+            alias,
+            position.peer_channels,
+            position.peer_closeness,
+            position.alternatives,
+            format!("{:?}", position.tier),
+            position.fee,
+            position
+                .suggested_fee
+                .map(|fee| fee.to_string())
+                .unwrap_or_else(|| "-".into())
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn analyze_fixture_graph() {
+        let snapshot: Snapshot =
+            serde_json::from_str(include_str!("../fixtures/listchannels.json")).unwrap();
+        let node_id = format!("02{}", "aa".repeat(32));
+        let analysis = analyze(&snapshot.channels, &HashMap::new(), &node_id);
+        assert_eq!(analysis.node_channels, 1);
+        // The peer is one hop away and the other nine active nodes two hops
+        assert!((analysis.node_closeness - 10.0 / 19.0).abs() < 1e-9);
+        assert_eq!(analysis.channels.len(), 1);
+        let position = &analysis.channels[0];
+        assert_eq!(position.short_channel_id, "750000x11x0");
+        assert_eq!(position.peer_channels, 10);
+        assert_eq!(position.peer_closeness, 1.0);
+        assert_eq!(position.alternatives, 9);
+        // Few enough alternatives for the medium tier, but the peer is more central than us
+        assert_eq!(position.tier, Tier::High);
+        assert_eq!(position.suggested_fee, Some(300));
+    }
+}
@@ -0,0 +1,359 @@
+use crate::db::{query_history, Decision, Decisions};
+use crate::pin::{pin_fee, unpin_fee};
+use crate::report::query_channel_statuses;
+use crate::rpc::Client;
+use crate::Cli;
+use log::{error, info, warn};
+use proto::feeder_server::{Feeder, FeederServer};
+use rusqlite::Connection;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::net::UnixListener;
+use tokio::sync::{Mutex, Notify};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{BroadcastStream, UnixListenerStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("feeder");
+}
+
+/// The gRPC control API. The client and database are shared with the main loop and always locked
+/// in that order. Only the decisions of the node of the service are streamed.
+#[derive(Clone)]
+pub struct FeederService {
+    cli: Arc<Cli>,
+    client: Arc<Mutex<Client>>,
+    db: Arc<Mutex<Connection>>,
+    decisions: Decisions,
+    trigger: Arc<Notify>,
+}
+impl FeederService {
+    pub fn new(
+        cli: Arc<Cli>,
+        client: Arc<Mutex<Client>>,
+        db: Arc<Mutex<Connection>>,
+        decisions: Decisions,
+        trigger: Arc<Notify>,
+    ) -> Self {
+        Self {
+            cli,
+            client,
+            db,
+            decisions,
+            trigger,
+        }
+    }
+}
+impl From<Decision> for proto::Decision {
+    fn from(decision: Decision) -> Self {
+        Self {
+            channel_id: decision.channel_id,
+            short_channel_id: decision.short_channel_id,
+            time: decision.time,
+            aggregates: decision.aggregates.map(|a| proto::Aggregates {
+                past_revenue: a.past_revenue,
+                average_revenue: a.average_revenue,
+                present_revenue: a.present_revenue,
+                current_revenue: a.current_revenue,
+                past_fee: a.past_fee,
+                average_fee: a.average_fee,
+                present_fee: a.present_fee,
+                current_fee: a.current_fee,
+            }),
+            reason: decision.reason,
+            proposed_fee: decision.proposed_fee,
+            applied_fee: decision.applied_fee,
+            success: decision.success,
+        }
+    }
+}
+
+type DecisionStream = Pin<Box<dyn Stream<Item = Result<proto::Decision, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Feeder for FeederService {
+    async fn list_channels(
+        &self,
+        _request: Request<proto::ListChannelsRequest>,
+    ) -> Result<Response<proto::ListChannelsResponse>, Status> {
+        let mut client = self.client.lock().await;
+        let mut db = self.db.lock().await;
+        let statuses = query_channel_statuses(&self.cli, &mut client, &mut db)
+            .await
+            .map_err(|e| Status::unavailable(format!("{:#}", e)))?;
+        let channels = statuses
+            .into_iter()
+            .map(|status| proto::Channel {
+                short_channel_id: status.short_channel_id,
+                channel_id: status.channel_id,
+                peer_id: status.peer_id,
+                alias: status.alias,
+                connected: status.connected,
+                state: status.state,
+                management: status.management,
+                offline_policy: format!("{:?}", self.cli.offline_policy),
+                fee: status.fee,
+                last_revenue: status.last_revenue,
+                epochs: status.epochs,
+                next_adjustment: status.next_adjustment,
+                last_decision: status.last_decision,
+                pinned_fee: status.pinned_fee,
+                pinned_until: status.pinned_until,
+                excluded: status.excluded,
+            })
+            .collect();
+        Ok(Response::new(proto::ListChannelsResponse { channels }))
+    }
+    async fn get_history(
+        &self,
+        request: Request<proto::GetHistoryRequest>,
+    ) -> Result<Response<proto::GetHistoryResponse>, Status> {
+        let request = request.into_inner();
+        let mut db = self.db.lock().await;
+        let values = query_history(Some(&request.channel), request.since, &mut db)
+            .into_iter()
+            .map(|values| proto::ChannelValues {
+                time: values.time,
+                short_channel_id: values.short_channel_id,
+                fee: values.fee,
+                revenue: values.revenue,
+            })
+            .collect();
+        Ok(Response::new(proto::GetHistoryResponse { values }))
+    }
+    async fn pin_fee(
+        &self,
+        request: Request<proto::PinFeeRequest>,
+    ) -> Result<Response<proto::PinFeeResponse>, Status> {
+        let request = request.into_inner();
+        let mut client = self.client.lock().await;
+        let mut db = self.db.lock().await;
+        match pin_fee(
+            &self.cli,
+            &request.channel,
+            request.fee,
+            request.until,
+            &mut client,
+            &mut db,
+            &self.decisions,
+        )
+        .await
+        {
+            Ok(Some(pin)) => Ok(Response::new(proto::PinFeeResponse {
+                short_channel_id: pin.short_channel_id,
+                channel_id: pin.channel_id,
+            })),
+            Ok(None) => Err(Status::not_found(format!(
+                "Channel {} is not known to the node",
+                request.channel
+            ))),
+            Err(e) => Err(Status::failed_precondition(format!("{:#}", e))),
+        }
+    }
+    async fn unpin_fee(
+        &self,
+        request: Request<proto::UnpinFeeRequest>,
+    ) -> Result<Response<proto::UnpinFeeResponse>, Status> {
+        let request = request.into_inner();
+        let mut db = self.db.lock().await;
+        match unpin_fee(&request.channel, &mut db, &self.decisions) {
+            Some(_) => Ok(Response::new(proto::UnpinFeeResponse {})),
+            None => Err(Status::not_found(format!(
+                "Channel {} is not pinned",
+                request.channel
+            ))),
+        }
+    }
+    async fn trigger_iteration(
+        &self,
+        _request: Request<proto::TriggerIterationRequest>,
+    ) -> Result<Response<proto::TriggerIterationResponse>, Status> {
+        info!("Iteration was triggered over gRPC");
+        self.trigger.notify_one();
+        Ok(Response::new(proto::TriggerIterationResponse {}))
+    }
+
+    type StreamDecisionsStream = DecisionStream;
+    async fn stream_decisions(
+        &self,
+        request: Request<proto::StreamDecisionsRequest>,
+    ) -> Result<Response<Self::StreamDecisionsStream>, Status> {
+        let channel = request.into_inner().channel;
+        let decisions = BroadcastStream::new(self.decisions.subscribe())
+            .filter_map(|decision| match decision {
+                Ok(decision) => Some(decision),
+                Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                    warn!("Decision stream skipped {} decisions", skipped);
+                    None
+                }
+            })
+            .filter(move |decision| match &channel {
+                Some(channel) => {
+                    *channel == decision.channel_id || *channel == decision.short_channel_id
+                }
+                None => true,
+            })
+            .map(proto::Decision::from)
+            .map(Ok);
+        Ok(Response::new(Box::pin(decisions)))
+    }
+}
+
+/// Serves the gRPC control API on a TCP address
+pub async fn serve_grpc(address: SocketAddr, service: FeederService) {
+    info!("Serving gRPC control API on {}", address);
+    if let Err(e) = Server::builder()
+        .add_service(FeederServer::new(service))
+        .serve(address)
+        .await
+    {
+        error!("gRPC server failed: {}", e);
+    }
+}
+/// Serves the gRPC control API on a Unix socket
+pub async fn serve_grpc_socket(path: PathBuf, service: FeederService) {
+    // A socket left behind by a previous run would make binding fail
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if metadata.file_type().is_socket() {
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Couldn't bind gRPC socket {:?}: {}", path, e);
+            return;
+        }
+    };
+    info!("Serving gRPC control API on {:?}", path);
+    if let Err(e) = Server::builder()
+        .add_service(FeederServer::new(service))
+        .serve_with_incoming(UnixListenerStream::new(listener))
+        .await
+    {
+        error!("gRPC server failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cln_grpc::stub::{StubNode, SHORT_CHANNEL_ID};
+    use crate::db::store_current_values;
+    use crate::migrations::migrate;
+    use clap::Parser;
+    use proto::feeder_client::FeederClient;
+    use tokio::sync::broadcast;
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    #[tokio::test]
+    async fn list_pin_and_stream_decisions() {
+        let stub = StubNode::start().await;
+        let client = stub.connect().await;
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db);
+        store_current_values(
+            &mut db,
+            &"ab".repeat(32),
+            SHORT_CHANNEL_ID.into(),
+            2000,
+            5000,
+        );
+        // The service only reads the options, the stub is already connected
+        let cli = Cli::parse_from(["cln-feeder", "--socket", "lightning-rpc"]);
+        let service = FeederService::new(
+            Arc::new(cli),
+            Arc::new(Mutex::new(client)),
+            Arc::new(Mutex::new(db)),
+            broadcast::channel(64).0,
+            Arc::new(Notify::new()),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(FeederServer::new(service))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let mut feeder = FeederClient::connect(format!("http://{}", address))
+            .await
+            .unwrap();
+
+        let channels = feeder
+            .list_channels(proto::ListChannelsRequest {})
+            .await
+            .unwrap()
+            .into_inner()
+            .channels;
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels[0].short_channel_id, SHORT_CHANNEL_ID);
+        assert_eq!(channels[0].alias.as_deref(), Some("peer"));
+        assert_eq!(channels[0].fee, 2000);
+        assert_eq!(channels[0].last_revenue, Some(5000));
+        assert_eq!(channels[0].pinned_fee, None);
+
+        let mut decisions = feeder
+            .stream_decisions(proto::StreamDecisionsRequest {
+                channel: Some(SHORT_CHANNEL_ID.into()),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let pinned = feeder
+            .pin_fee(proto::PinFeeRequest {
+                channel: "ab".repeat(32),
+                fee: 1500,
+                until: None,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(pinned.short_channel_id, SHORT_CHANNEL_ID);
+        assert_eq!(
+            *stub.fees.lock().unwrap(),
+            vec![(SHORT_CHANNEL_ID.into(), 1500)]
+        );
+        let decision = decisions.message().await.unwrap().unwrap();
+        assert_eq!(decision.reason, "Fee is pinned at 1500 ppm");
+        assert_eq!(decision.applied_fee, Some(1500));
+        assert_eq!(decision.success, Some(true));
+
+        let history = feeder
+            .get_history(proto::GetHistoryRequest {
+                channel: SHORT_CHANNEL_ID.into(),
+                since: None,
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .values;
+        assert_eq!(history.len(), 1);
+        assert_eq!((history[0].fee, history[0].revenue), (2000, 5000));
+
+        let channels = feeder
+            .list_channels(proto::ListChannelsRequest {})
+            .await
+            .unwrap()
+            .into_inner()
+            .channels;
+        assert_eq!(
+            (channels[0].fee, channels[0].pinned_fee),
+            (1500, Some(1500))
+        );
+        let unknown = feeder
+            .pin_fee(proto::PinFeeRequest {
+                channel: "1x1x1".into(),
+                fee: 1500,
+                until: None,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(unknown.code(), tonic::Code::NotFound);
+    }
+}
@@ -0,0 +1,171 @@
+use crate::db::{query_decisions, query_history};
+use crate::metrics::LAST_SUCCESSFUL_ITERATION;
+use crate::report::query_channel_statuses;
+use crate::rpc::Client;
+use crate::{parse_time, Cli, ITERATION_INTERVAL};
+use chrono::Utc;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use rusqlite::Connection;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// The JSON API. The client and database are shared with the main loop and always locked in
+/// that order.
+#[derive(Clone)]
+pub struct JsonApi {
+    cli: Arc<Cli>,
+    client: Arc<Mutex<Client>>,
+    db: Arc<Mutex<Connection>>,
+}
+impl JsonApi {
+    pub fn new(cli: Arc<Cli>, client: Arc<Mutex<Client>>, db: Arc<Mutex<Connection>>) -> Self {
+        Self { cli, client, db }
+    }
+    async fn handle(self, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        if request.method() != Method::GET {
+            return Ok(error_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Only GET is supported",
+            ));
+        }
+        let query = parse_query(request.uri().query());
+        let segments: Vec<&str> = request.uri().path().trim_matches('/').split('/').collect();
+        let response = match segments.as_slice() {
+            ["health"] => health(&self.cli.node_name, Utc::now().timestamp()),
+            ["channels"] => {
+                let mut client = self.client.lock().await;
+                let mut db = self.db.lock().await;
+                match query_channel_statuses(&self.cli, &mut client, &mut db).await {
+                    Ok(statuses) => json_response(StatusCode::OK, &statuses),
+                    Err(e) => error_response(StatusCode::SERVICE_UNAVAILABLE, &format!("{:#}", e)),
+                }
+            }
+            ["channels", channel, "history"] => {
+                history(&channel.to_string(), &query, &mut *self.db.lock().await)
+            }
+            ["decisions"] => decisions(&query, &mut *self.db.lock().await),
+            _ => error_response(StatusCode::NOT_FOUND, "Not found"),
+        };
+        Ok(response)
+    }
+}
+
+fn json_response<T: Serialize + ?Sized>(status: StatusCode, value: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::to_vec(value).expect("Couldn't serialize response"),
+        ))
+        .unwrap()
+}
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, &json!({ "error": message }))
+}
+/// Parses the parameters of a query string. Values aren't percent-decoded because channel ids,
+/// dates and numbers don't need to be encoded.
+fn parse_query(query: Option<&str>) -> HashMap<String, String> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => (pair.to_string(), String::new()),
+        })
+        .collect()
+}
+fn parse_since(query: &HashMap<String, String>) -> Result<Option<i64>, String> {
+    query
+        .get("since")
+        .map(|since| since.parse::<i64>().or_else(|_| parse_time(since)))
+        .transpose()
+}
+
+/// Healthy while the last successful iteration is at most three intervals ago
+fn health(node: &str, now: i64) -> Response<Body> {
+    let last_iteration = LAST_SUCCESSFUL_ITERATION.with_label_values(&[node]).get();
+    let healthy = last_iteration > 0 && now - last_iteration <= 3 * ITERATION_INTERVAL as i64;
+    json_response(
+        if healthy {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+        &json!({
+            "healthy": healthy,
+            "last_successful_iteration": (last_iteration > 0).then_some(last_iteration),
+        }),
+    )
+}
+fn history(
+    channel: &String,
+    query: &HashMap<String, String>,
+    db: &mut Connection,
+) -> Response<Body> {
+    match parse_since(query) {
+        Ok(since) => json_response(StatusCode::OK, &query_history(Some(channel), since, db)),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, &e),
+    }
+}
+fn decisions(query: &HashMap<String, String>, db: &mut Connection) -> Response<Body> {
+    let since = match parse_since(query) {
+        Ok(since) => since,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+    };
+    let count = match query.get("count").map(|count| count.parse::<u32>()) {
+        None => 20,
+        Some(Ok(count)) => count,
+        Some(Err(_)) => return error_response(StatusCode::BAD_REQUEST, "count must be a number"),
+    };
+    json_response(
+        StatusCode::OK,
+        &query_decisions(query.get("channel"), since, count, db),
+    )
+}
+
+/// Serves the read-only JSON API on `/channels`, `/channels/{id}/history`, `/decisions` and
+/// `/health`
+pub async fn serve_json_api(address: SocketAddr, api: JsonApi) {
+    info!("Serving JSON API on http://{}", address);
+    let service = make_service_fn(move |_| {
+        let api = api.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| api.clone().handle(request))) }
+    });
+    if let Err(e) = Server::bind(&address).serve(service).await {
+        error!("JSON API server failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::store_current_values;
+    use crate::migrations::migrate;
+    #[tokio::test]
+    async fn serve_history_and_reject_bad_parameters() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db);
+        store_current_values(&mut db, &"ab".repeat(32), "1x2x3".into(), 100, 2000);
+
+        let response = history(&"1x2x3".into(), &parse_query(Some("since=0")), &mut db);
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let values: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(values[0]["short_channel_id"], "1x2x3");
+        assert_eq!(values[0]["fee"], 100);
+        assert_eq!(values[0]["revenue"], 2000);
+
+        let response = history(&"1x2x3".into(), &parse_query(Some("since=never")), &mut db);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = decisions(&parse_query(Some("channel=1x2x3&count=all")), &mut db);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
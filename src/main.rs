@@ -1,26 +1,128 @@
+mod bootstrap;
+mod cln_grpc;
+mod coordinate;
 mod db;
+mod explain;
+mod export;
+mod filter;
+mod gossip;
+mod graph;
+mod grpc;
+mod http;
+mod metrics;
+mod migrations;
+mod nodes;
+mod pin;
+mod report;
+mod retention;
 mod rpc;
+mod uptime;
+mod warmup;
 
-use crate::db::{create_table, query_last_channel_values, store_current_values};
-use crate::rpc::{get_current_fees, get_revenue_since, set_channel_fee};
-use anyhow::Result;
-use chrono::{Duration, Utc};
-use clap::Parser;
+use crate::bootstrap::bootstrap;
+use crate::coordinate::{iterate_peer, CoordinatedChannel};
+use crate::db::{
+    close_offline_interval, link_channel_id, query_epoch_count, query_has_applied_decision,
+    query_has_history, query_last_applied_fee, query_last_channel_values,
+    query_last_short_channel_id, query_offline_seconds_since, query_open_offline_interval,
+    query_pin, remove_expired_pins, remove_peer_observations_before, scope_to_node,
+    store_current_values, store_decision, store_external_change, store_offline_interval,
+    store_peer_observation, store_pin, Aggregates, Decision, Decisions, Pin,
+};
+use crate::explain::explain;
+use crate::export::{export, Format, Table};
+use crate::filter::exclusion;
+use crate::gossip::{bound_by_competitors, parse_percentile};
+use crate::graph::{analyze_live, analyze_snapshot};
+use crate::grpc::{serve_grpc, serve_grpc_socket, FeederService};
+use crate::http::{serve_json_api, JsonApi};
+use crate::metrics::{
+    serve_metrics, CHANNEL_BALANCE_RATIO, CHANNEL_EPOCH_FORWARDS, CHANNEL_EPOCH_REVENUE,
+    CHANNEL_FEE, FEE_CHANGES, ITERATION_DURATION, LAST_SUCCESSFUL_ITERATION,
+};
+use crate::migrations::migrate;
+use crate::nodes::load_nodes;
+use crate::pin::{pin_fee, pin_reason, unpin_fee};
+use crate::report::{print_decisions, print_status, print_statuses, query_channel_statuses};
+use crate::retention::{
+    mark_closed_channels, peers_with_open_channels, prune_closed_channels, RetentionAction,
+};
+use crate::rpc::{
+    get_current_channels, get_current_peers, get_forwards_since, get_info, get_node_id,
+    set_channel_fee, Client, Forwards, Management,
+};
+use crate::uptime::{peer_is_flaky, print_uptime_report};
+use crate::warmup::{warmup_fee, WarmupPolicy, WARMUP_REASON};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
 use cln_rpc::primitives::ShortChannelId;
 use cln_rpc::ClnRpc;
 use env_logger::WriteStyle;
-use log::{debug, info, trace, LevelFilter};
+use log::{debug, error, info, trace, warn, LevelFilter};
 use rusqlite::Connection;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Display;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex, Notify};
 
-#[derive(Parser)]
+#[derive(Clone, Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Path to the CLN Socket. Usually in `./clightning/bitcoin/lightning-rpc`
-    #[clap(short, long, value_parser, value_name = "PATH")]
-    socket: PathBuf,
+    #[clap(
+        short,
+        long,
+        value_parser,
+        value_name = "PATH",
+        required_unless_present_any = &["nodes", "grpc-endpoint"]
+    )]
+    socket: Option<PathBuf>,
+
+    /// Connect to cln-grpc on this URL instead of the CLN socket, e.g. `https://127.0.0.1:9736`
+    #[clap(
+        long,
+        value_name = "URL",
+        conflicts_with = "socket",
+        requires_all = &["grpc-ca-cert", "grpc-client-cert", "grpc-client-key"]
+    )]
+    grpc_endpoint: Option<String>,
+
+    /// The CA certificate of cln-grpc. Usually `ca.pem` in the network directory of CLN
+    #[clap(long, value_parser, value_name = "PATH")]
+    grpc_ca_cert: Option<PathBuf>,
+
+    /// The client certificate for cln-grpc. Usually `client.pem` in the network directory of CLN
+    #[clap(long, value_parser, value_name = "PATH")]
+    grpc_client_cert: Option<PathBuf>,
+
+    /// The key of the client certificate. Usually `client-key.pem` in the network directory of CLN
+    #[clap(long, value_parser, value_name = "PATH")]
+    grpc_client_key: Option<PathBuf>,
+
+    /// A JSON file with several nodes to manage from this process. Each node has a name, a
+    /// socket and arguments that override the options for it
+    #[clap(
+        long,
+        value_parser,
+        value_name = "PATH",
+        conflicts_with_all = &["socket", "grpc-endpoint"]
+    )]
+    nodes: Option<PathBuf>,
+
+    /// Only run for this node of the nodes file
+    #[clap(long, value_name = "NAME", requires = "nodes")]
+    node: Option<String>,
+
+    /// The name of the node in the nodes file, empty when managing a single node
+    #[clap(skip)]
+    node_name: String,
 
     /// Path to the data directory that feeder uses
     #[clap(
@@ -55,6 +157,236 @@ struct Cli {
     /// The length of an epoch in hours
     #[clap(short = 'E', long, default_value_t = 24, value_name = "HOURS")]
     epoch_length: u32,
+
+    /// How to handle channels whose peer is offline
+    #[clap(short = 'o', long, value_enum, default_value_t = OfflinePolicy::Keep, value_name = "POLICY")]
+    offline_policy: OfflinePolicy,
+
+    /// The fee that channels are raised to while their peer is offline when using the `deterrent` offline policy
+    #[clap(long, default_value_t = 5000, value_name = "PPM")]
+    offline_fee: u32,
+
+    /// How to handle fees that were changed by someone other than the feeder
+    #[clap(long, value_enum, default_value_t = ExternalFeePolicy::Adopt, value_name = "POLICY")]
+    external_fee_policy: ExternalFeePolicy,
+
+    /// Windows in hours over which the uptime of peers is reported
+    #[clap(long, value_delimiter = ',', default_values_t = vec![24, 168, 720], value_name = "HOURS")]
+    uptime_windows: Vec<u32>,
+
+    /// Peers with an uptime in percent below this are considered unreliable. Fees of their channels aren't lowered.
+    #[clap(long, default_value_t = 90.0, value_name = "PERCENT")]
+    flaky_threshold: f64,
+
+    /// Serve metrics for Prometheus on this address, e.g. `127.0.0.1:9750`
+    #[clap(long, value_name = "ADDRESS")]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Allow the metrics to be served on addresses other than localhost
+    #[clap(long, action)]
+    metrics_allow_remote: bool,
+
+    /// Serve the gRPC control API on this address, e.g. `127.0.0.1:9751`
+    #[clap(long, value_name = "ADDRESS")]
+    grpc_listen: Option<SocketAddr>,
+
+    /// Serve the gRPC control API on this Unix socket
+    #[clap(long, value_name = "PATH")]
+    grpc_socket: Option<PathBuf>,
+
+    /// Allow the gRPC control API to listen on addresses other than localhost
+    #[clap(long, action)]
+    grpc_allow_remote: bool,
+
+    /// Serve a read-only JSON API for dashboards on this address, e.g. `127.0.0.1:9752`
+    #[clap(long, value_name = "ADDRESS")]
+    http_listen: Option<SocketAddr>,
+
+    /// Allow the JSON API to listen on addresses other than localhost
+    #[clap(long, action)]
+    http_allow_remote: bool,
+
+    /// Only manage these channels by short channel id or channel id
+    #[clap(long, value_delimiter = ',', value_name = "IDS")]
+    include_channels: Vec<String>,
+
+    /// Don't manage these channels by short channel id or channel id
+    #[clap(long, value_delimiter = ',', value_name = "IDS")]
+    exclude_channels: Vec<String>,
+
+    /// Only manage channels with these peers by node id
+    #[clap(long, value_delimiter = ',', value_name = "NODE IDS")]
+    include_peers: Vec<String>,
+
+    /// Don't manage channels with these peers by node id
+    #[clap(long, value_delimiter = ',', value_name = "NODE IDS")]
+    exclude_peers: Vec<String>,
+
+    /// Don't manage private channels
+    #[clap(long, action)]
+    exclude_private: bool,
+
+    /// Don't manage channels with a capacity below this
+    #[clap(long, value_name = "SATS")]
+    min_capacity: Option<u64>,
+
+    /// Coordinate the fees of all channels to a peer. One fee is determined from their combined
+    /// revenue and applied to all of them.
+    #[clap(long, action)]
+    coordinate_peers: bool,
+
+    /// Never raise fees above this percentile of the fees other nodes charge into the same peer
+    #[clap(long, value_parser = parse_percentile, value_name = "PERCENTILE")]
+    competitor_ceiling: Option<f64>,
+
+    /// Never lower fees below this percentile of the fees other nodes charge into the same peer
+    #[clap(long, value_parser = parse_percentile, value_name = "PERCENTILE")]
+    competitor_floor: Option<f64>,
+
+    /// How to set the fee of channels that appear after the feeder started managing the node
+    #[clap(long, value_enum, default_value_t = WarmupPolicy::None, value_name = "POLICY")]
+    warmup_policy: WarmupPolicy,
+
+    /// The fee that new channels start at with the `fixed` warmup policy and when the other
+    /// policies can't derive one
+    #[clap(long, default_value_t = 100, value_name = "PPM")]
+    warmup_fee: u32,
+
+    /// The percentile of competitor fees that new channels start at with the `competitor` warmup
+    /// policy
+    #[clap(long, value_parser = parse_percentile, default_value_t = 50.0, value_name = "PERCENTILE")]
+    warmup_percentile: f64,
+
+    /// Reconstruct the history of channels without history from their past forwards before the
+    /// first iteration
+    #[clap(long, action)]
+    bootstrap: bool,
+
+    /// Prune the history of channels that closed more than this many days ago and of peers without open channels
+    #[clap(long, value_name = "DAYS")]
+    retention_days: Option<u32>,
+
+    /// What to do with the history of closed channels when it is pruned
+    #[clap(long, value_enum, default_value_t = RetentionAction::Archive, value_name = "ACTION")]
+    retention_action: RetentionAction,
+}
+
+/// The seconds between iterations over all channels
+const ITERATION_INTERVAL: u64 = 600;
+
+#[derive(Clone, Subcommand)]
+enum Command {
+    /// Reconstruct the history of channels without history from their past forwards
+    Bootstrap,
+    /// Report the uptime of all observed peers and the ones that are unreliable
+    Uptime,
+    /// List the latest fee decisions and the reasoning behind them
+    Decisions {
+        /// Only list decisions for this short channel id or channel id
+        #[clap(short, long, value_name = "ID")]
+        channel: Option<String>,
+
+        /// The number of decisions to list
+        #[clap(short = 'n', long, default_value_t = 20)]
+        count: u32,
+    },
+    /// Show what the feeder currently knows about each channel
+    Status {
+        /// Print the status as JSON
+        #[clap(long, action)]
+        json: bool,
+    },
+    /// Export the history of the database
+    Export {
+        /// The table to export
+        #[clap(long, value_enum, default_value_t = Table::History)]
+        table: Table,
+
+        /// The format to export the table in
+        #[clap(short, long, value_enum, default_value_t = Format::Csv)]
+        format: Format,
+
+        /// Only export rows of this short channel id or channel id
+        #[clap(short, long, value_name = "ID")]
+        channel: Option<String>,
+
+        /// Only export rows since this date, e.g. `2022-09-01` or `2022-09-01T12:00:00Z`
+        #[clap(long, value_parser = parse_time, value_name = "DATE")]
+        since: Option<i64>,
+    },
+    /// Explain the fee that would be proposed for a channel right now without changing it
+    Explain {
+        /// The short channel id or channel id of the channel
+        #[clap(value_name = "ID")]
+        channel: String,
+    },
+    /// Pin the fee of a channel so that the feeder leaves it alone
+    Pin {
+        /// The short channel id or channel id of the channel
+        #[clap(value_name = "ID")]
+        channel: String,
+
+        /// The fee to pin
+        #[clap(value_name = "PPM")]
+        fee: u32,
+
+        /// Remove the pin at this date, e.g. `2022-09-01` or `2022-09-01T12:00:00Z`
+        #[clap(long, value_parser = parse_time, value_name = "DATE")]
+        until: Option<i64>,
+    },
+    /// Analyze the position of our channels in the graph and suggest fee tiers for them
+    GraphAnalyze {
+        /// Analyze a `listchannels` and `listnodes` dump instead of the current graph of the node
+        #[clap(long, value_name = "PATH", requires = "node-id")]
+        snapshot: Option<PathBuf>,
+
+        /// The node id of our node. Needed to analyze a snapshot
+        #[clap(long, value_name = "ID")]
+        node_id: Option<String>,
+
+        /// Print the analysis as JSON
+        #[clap(long, action)]
+        json: bool,
+    },
+    /// Remove the pin of a channel so that the feeder manages it again
+    Unpin {
+        /// The short channel id or channel id of the channel
+        #[clap(value_name = "ID")]
+        channel: String,
+    },
+}
+
+/// Parses a date or a RFC 3339 timestamp into a unix timestamp
+fn parse_time(time: &str) -> Result<i64, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Ok(time.timestamp());
+    }
+    NaiveDate::parse_from_str(time, "%Y-%m-%d")
+        .map(|date| {
+            Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap())
+                .timestamp()
+        })
+        .map_err(|_| format!("{} is neither a date nor a RFC 3339 timestamp", time))
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum OfflinePolicy {
+    /// Leave the fee alone and scale the epoch revenue to the time the peer was online
+    Keep,
+    /// Raise the fee to the offline fee until the peer returns
+    Deterrent,
+    /// Hold the fee and pause the current epoch until the peer returns
+    Hold,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum ExternalFeePolicy {
+    /// Continue managing the channel from the new fee
+    Adopt,
+    /// Set the fee back to the one the feeder set last
+    Revert,
+    /// Pin the new fee until the channel is unpinned
+    Pause,
 }
 
 #[allow(clippy::let_unit_value)]
@@ -82,112 +414,820 @@ async fn main() -> Result<()> {
             .init();
     };
 
-    info!("Creating RPC connection to CLN on {:?}", cli.socket);
-    let mut client = ClnRpc::new(cli.socket)
-        .await
-        .expect("Couldn't connect to RPC Socket");
-
+    let mut nodes = match &cli.nodes {
+        Some(path) => {
+            assert!(
+                cli.grpc_listen.is_none() && cli.grpc_socket.is_none() && cli.http_listen.is_none(),
+                "With several nodes the APIs are configured in the arguments of each node"
+            );
+            let nodes = load_nodes(&cli, path)?;
+            match &cli.node {
+                Some(name) => vec![nodes
+                    .into_iter()
+                    .find(|node| &node.node_name == name)
+                    .ok_or_else(|| anyhow!("Node {} is not in the nodes file", name))?],
+                None => nodes,
+            }
+        }
+        None => vec![cli.clone()],
+    };
+    if cli.command.is_none() {
+        if let Some(address) = cli.metrics_listen {
+            assert!(
+                address.ip().is_loopback() || cli.metrics_allow_remote,
+                "Metrics are only served on localhost unless remote access is allowed"
+            );
+            tokio::spawn(serve_metrics(address));
+        }
+    }
+    match nodes.len() {
+        0 => bail!("There are no nodes to manage"),
+        1 => return run(Arc::new(nodes.remove(0))).await,
+        _ => {}
+    }
+    match &cli.command {
+        Some(Command::Status { json }) => print_status_of_nodes(nodes, *json).await,
+        Some(_) => bail!("Select the node to run the subcommand for with --node"),
+        None => {
+            let handles: Vec<_> = nodes
+                .into_iter()
+                .map(|node| (node.node_name.clone(), tokio::spawn(run(Arc::new(node)))))
+                .collect();
+            for (name, handle) in handles {
+                match handle.await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => error!("Node {} stopped: {:#}", name, e),
+                    Err(e) => error!("Node {} stopped: {}", name, e),
+                }
+            }
+            Ok(())
+        }
+    }
+}
+async fn open_database(cli: &Cli) -> Connection {
     let db_path = cli.data_dir.join("./feeder.sqlite");
-
     info!("Connecting to database {:?}", db_path);
     let mut db = if cli.temp_database {
         Connection::open_in_memory().expect("Couldn't open database in memory")
     } else {
-        tokio::fs::create_dir_all(cli.data_dir)
+        tokio::fs::create_dir_all(&cli.data_dir)
             .await
             .expect("Couldn't create data dir");
         Connection::open(db_path).expect("Couldn't open database")
     };
-    create_table(&mut db);
+    migrate(&mut db);
+    db
+}
+/// Connects to the node and makes sure that the database belongs to it
+async fn connect(cli: &Cli, db: &mut Connection) -> Result<Client> {
+    // The endpoint requires all certificates
+    let grpc = (
+        &cli.grpc_endpoint,
+        &cli.grpc_ca_cert,
+        &cli.grpc_client_cert,
+        &cli.grpc_client_key,
+    );
+    let mut client =
+        if let (Some(endpoint), Some(ca_cert), Some(client_cert), Some(client_key)) = grpc {
+            info!("Creating gRPC connection to CLN on {}", endpoint);
+            Client::grpc(
+                &cli.node_name,
+                cln_grpc::connect(endpoint, ca_cert, client_cert, client_key).await?,
+            )
+        } else {
+            let socket = cli.socket.as_ref().expect("A socket is required");
+            info!("Creating RPC connection to CLN on {:?}", socket);
+            Client::socket(
+                &cli.node_name,
+                ClnRpc::new(socket)
+                    .await
+                    .expect("Couldn't connect to RPC Socket"),
+            )
+        };
+    let info = get_info(&mut client).await?;
+    info!("Connected to node {} on {}", info.id, info.network);
+    scope_to_node(&info.id, &info.network, db)?;
+    Ok(client)
+}
+/// Prints the status of the channels of each node
+async fn print_status_of_nodes(nodes: Vec<Cli>, json: bool) -> Result<()> {
+    let mut statuses = BTreeMap::new();
+    for node in nodes {
+        let mut db = open_database(&node).await;
+        let mut client = connect(&node, &mut db).await?;
+        let node_statuses = query_channel_statuses(&node, &mut client, &mut db).await?;
+        statuses.insert(node.node_name, node_statuses);
+    }
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&statuses).expect("Couldn't serialize status")
+        );
+        return Ok(());
+    }
+    for (name, statuses) in statuses {
+        println!("Node {}", name);
+        print_statuses(statuses);
+        println!();
+    }
+    Ok(())
+}
+/// Runs a subcommand or manages the channels of one node
+async fn run(cli: Arc<Cli>) -> Result<()> {
     assert!(
         cli.adjustment_divisor != 0,
         "The divisor must be bigger than 0"
     );
+    if let Some(address) = cli.grpc_listen {
+        assert!(
+            address.ip().is_loopback() || cli.grpc_allow_remote,
+            "The gRPC control API only listens on localhost unless remote access is allowed"
+        );
+    }
+    if let Some(address) = cli.http_listen {
+        assert!(
+            address.ip().is_loopback() || cli.http_allow_remote,
+            "The JSON API only listens on localhost unless remote access is allowed"
+        );
+    }
+    if let (Some(floor), Some(ceiling)) = (cli.competitor_floor, cli.competitor_ceiling) {
+        assert!(
+            floor <= ceiling,
+            "The competitor floor must not be above the ceiling"
+        );
+    }
 
+    let mut db = open_database(&cli).await;
+    let decisions: Decisions = broadcast::channel(64).0;
+
+    match &cli.command {
+        Some(Command::Uptime) => {
+            print_uptime_report(&cli.uptime_windows, cli.flaky_threshold, &mut db);
+            return Ok(());
+        }
+        Some(Command::Decisions { channel, count }) => {
+            print_decisions(channel.as_ref(), *count, &mut db);
+            return Ok(());
+        }
+        Some(Command::Export {
+            table,
+            format,
+            channel,
+            since,
+        }) => {
+            export(
+                *table,
+                *format,
+                channel.as_ref(),
+                *since,
+                &mut db,
+                std::io::stdout(),
+            );
+            return Ok(());
+        }
+        Some(Command::Unpin { channel }) => {
+            if unpin_fee(channel, &mut db, &decisions).is_none() {
+                println!("Channel {} is not pinned", channel);
+            }
+            return Ok(());
+        }
+        Some(Command::GraphAnalyze {
+            snapshot: Some(snapshot),
+            node_id,
+            json,
+        }) => {
+            let node_id = node_id.as_ref().expect("A snapshot requires the node id");
+            return analyze_snapshot(snapshot, node_id, *json);
+        }
+        Some(Command::Bootstrap)
+        | Some(Command::Status { .. })
+        | Some(Command::Explain { .. })
+        | Some(Command::Pin { .. })
+        | Some(Command::GraphAnalyze { .. })
+        | None => {}
+    }
+
+    let mut client = connect(&cli, &mut db).await?;
+
+    match &cli.command {
+        Some(Command::Status { json }) => {
+            return print_status(&cli, *json, &mut client, &mut db).await;
+        }
+        Some(Command::Explain { channel }) => {
+            return explain(&cli, channel, &mut client, &mut db).await;
+        }
+        Some(Command::Bootstrap) => {
+            return bootstrap(&cli, &mut client, &mut db).await;
+        }
+        Some(Command::GraphAnalyze { node_id, json, .. }) => {
+            return analyze_live(node_id.as_ref(), *json, &mut client).await;
+        }
+        Some(Command::Pin {
+            channel,
+            fee,
+            until,
+        }) => {
+            if pin_fee(
+                &cli,
+                channel,
+                *fee,
+                *until,
+                &mut client,
+                &mut db,
+                &decisions,
+            )
+            .await?
+            .is_none()
+            {
+                println!("Channel {} is not known to the node", channel);
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    if cli.bootstrap {
+        bootstrap(&cli, &mut client, &mut db).await?;
+    }
+    let client = Arc::new(Mutex::new(client));
+    let db = Arc::new(Mutex::new(db));
+    let trigger = Arc::new(Notify::new());
+    let service = FeederService::new(
+        cli.clone(),
+        client.clone(),
+        db.clone(),
+        decisions.clone(),
+        trigger.clone(),
+    );
+    if let Some(address) = cli.grpc_listen {
+        tokio::spawn(serve_grpc(address, service.clone()));
+    }
+    if let Some(path) = &cli.grpc_socket {
+        tokio::spawn(serve_grpc_socket(path.clone(), service));
+    }
+    if let Some(address) = cli.http_listen {
+        let api = JsonApi::new(cli.clone(), client.clone(), db.clone());
+        tokio::spawn(serve_json_api(address, api));
+    }
     loop {
         trace!("New Iteration");
-        iterate(
-            cli.epochs,
-            cli.epoch_length,
-            cli.adjustment_divisor,
-            &mut client,
-            &mut db,
+        let timer = ITERATION_DURATION
+            .with_label_values(&[&cli.node_name])
+            .start_timer();
+        let result = iterate(
+            &cli,
+            &mut *client.lock().await,
+            &mut *db.lock().await,
+            &decisions,
         )
         .await;
-        tokio::time::sleep(std::time::Duration::from_secs(600)).await;
+        match result {
+            Ok(()) => LAST_SUCCESSFUL_ITERATION
+                .with_label_values(&[&cli.node_name])
+                .set(Utc::now().timestamp()),
+            Err(e) => error!("Iteration failed: {:#}", e),
+        }
+        timer.observe_duration();
+        tokio::select! {
+            _ = tokio::time::sleep(std::time::Duration::from_secs(ITERATION_INTERVAL)) => {}
+            _ = trigger.notified() => {}
+        }
     }
 }
 async fn iterate(
-    epochs: u32,
-    epoch_length: u32,
-    adjustment_divisor: u32,
-    client: &mut ClnRpc,
+    cli: &Cli,
+    client: &mut Client,
     db: &mut Connection,
-) {
-    let current_fees = get_current_fees(client).await;
-    for (id, current_fee) in current_fees {
-        let last_values = query_last_channel_values(&id, epochs, db);
-        trace!("{}: Queried last channel values", id);
-
-        let last_updated = {
-            if let Some((last_updated, _, _)) = last_values.first() {
-                if last_updated > &(Utc::now() - Duration::hours(epoch_length.into())).timestamp() {
-                    trace!(
-                        "{}: Skipped iteration because current epoch is still ongoing",
-                        id
+    decisions: &Decisions,
+) -> Result<()> {
+    let peers = get_current_peers(client).await?;
+    for peer in &peers {
+        store_peer_observation(db, &peer.id.to_string(), peer.connected);
+    }
+    // Observations are kept as long as any uptime window or the flaky check looks at them
+    let observed_hours = cli
+        .uptime_windows
+        .iter()
+        .copied()
+        .chain([cli.epochs * cli.epoch_length])
+        .max()
+        .unwrap();
+    let removed = remove_peer_observations_before(
+        db,
+        (Utc::now() - Duration::hours(observed_hours.into())).timestamp(),
+    );
+    if removed > 0 {
+        debug!(
+            "Removed {} peer observations older than {}h",
+            removed, observed_hours
+        );
+    }
+    for pin in remove_expired_pins(db, Utc::now().timestamp()) {
+        info!("{}: Pin expired. Resuming management", pin.short_channel_id);
+        store_decision(
+            db,
+            &Decision {
+                channel_id: pin.channel_id,
+                short_channel_id: pin.short_channel_id,
+                time: Utc::now().timestamp(),
+                aggregates: None,
+                reason: String::from("Pin expired"),
+                proposed_fee: None,
+                applied_fee: None,
+                success: None,
+            },
+            decisions,
+        );
+    }
+    // Our node id is only needed to tell our channels apart from competitors in the graph
+    let node_id = if cli.competitor_floor.is_some()
+        || cli.competitor_ceiling.is_some()
+        || cli.warmup_policy == WarmupPolicy::Competitor
+    {
+        Some(get_node_id(client).await?)
+    } else {
+        None
+    };
+    // Without any history the feeder is new to the node and its channels aren't new
+    let has_history = query_has_history(db);
+    mark_closed_channels(&cli.node_name, &peers, Utc::now().timestamp(), db);
+    let open_peers = peers_with_open_channels(&peers);
+    let current_channels = get_current_channels(peers);
+    let managed_fees: Vec<(String, u32)> = current_channels
+        .iter()
+        .filter(|(_, channel)| channel.management == Management::Managed)
+        .map(|(id, channel)| (id.clone(), channel.fee))
+        .collect();
+    let mut coordinated: HashMap<String, Vec<CoordinatedChannel>> = HashMap::new();
+    for (id, channel) in current_channels {
+        CHANNEL_FEE
+            .with_label_values(&[&cli.node_name, &id])
+            .set(channel.fee.into());
+        CHANNEL_BALANCE_RATIO
+            .with_label_values(&[&cli.node_name, &id])
+            .set(channel.balance_ratio());
+        match channel.management {
+            Management::Managed => {
+                trace!("{}: Managing channel in state {}", id, channel.state)
+            }
+            Management::Frozen => {
+                debug!("{}: Fee is frozen in state {}", id, channel.state);
+                continue;
+            }
+            Management::Closing => {
+                debug!(
+                    "{}: Not managing closing channel in state {}",
+                    id, channel.state
+                );
+                continue;
+            }
+        }
+        let channel_id = &channel.channel_id;
+        link_channel_id(db, channel_id, &id);
+        if let Some(last_id) = query_last_short_channel_id(channel_id, db) {
+            if last_id != id {
+                info!(
+                    "{}: Short channel id changed from {}. Continuing its history",
+                    id, last_id
+                );
+            }
+        }
+        let mut pin = query_pin(channel_id, db);
+        let excluded = exclusion(cli, &id, &channel);
+        if !channel.connected {
+            if query_open_offline_interval(channel_id, db).is_none() {
+                info!("{}: Peer went offline", id);
+                let fee_before = if cli.offline_policy == OfflinePolicy::Deterrent
+                    && pin.is_none()
+                    && excluded.is_none()
+                    && channel.fee < cli.offline_fee
+                {
+                    info!(
+                        "{}: Raising fee while peer is offline {} -> {} ppm",
+                        id, channel.fee, cli.offline_fee
                     );
-                    continue;
+                    let success = set_channel_fee(client, &id, cli.offline_fee).await;
+                    if success {
+                        FEE_CHANGES.with_label_values(&[&cli.node_name]).inc();
+                    }
+                    store_decision(
+                        db,
+                        &Decision {
+                            channel_id: channel_id.clone(),
+                            short_channel_id: id.clone(),
+                            time: Utc::now().timestamp(),
+                            aggregates: None,
+                            reason: String::from("Peer went offline"),
+                            proposed_fee: Some(cli.offline_fee),
+                            applied_fee: Some(cli.offline_fee),
+                            success: Some(success),
+                        },
+                        decisions,
+                    );
+                    Some(channel.fee)
                 } else {
-                    *last_updated
+                    None
+                };
+                store_offline_interval(db, channel_id, fee_before);
+            }
+            trace!("{}: Skipped iteration because peer is offline", id);
+            continue;
+        }
+        let mut current_fee = channel.fee;
+        if let Some((went_offline, fee_before)) = query_open_offline_interval(channel_id, db) {
+            info!(
+                "{}: Peer came back online after {} minutes",
+                id,
+                (Utc::now().timestamp() - went_offline) / 60
+            );
+            close_offline_interval(db, channel_id);
+            if let Some(fee_before) = fee_before {
+                info!(
+                    "{}: Restoring fee from before peer went offline {} -> {} ppm",
+                    id, current_fee, fee_before
+                );
+                let success = set_channel_fee(client, &id, fee_before).await;
+                if success {
+                    FEE_CHANGES.with_label_values(&[&cli.node_name]).inc();
                 }
-            } else {
-                (Utc::now() - Duration::hours(epoch_length.into())).timestamp()
+                store_decision(
+                    db,
+                    &Decision {
+                        channel_id: channel_id.clone(),
+                        short_channel_id: id.clone(),
+                        time: Utc::now().timestamp(),
+                        aggregates: None,
+                        reason: String::from("Peer came back online"),
+                        proposed_fee: Some(fee_before),
+                        applied_fee: Some(fee_before),
+                        success: Some(success),
+                    },
+                    decisions,
+                );
+                current_fee = fee_before;
             }
-        };
+        }
+        if let (None, None, Some(last_fee)) =
+            (&pin, &excluded, query_last_applied_fee(channel_id, db))
+        {
+            if current_fee != last_fee {
+                warn!(
+                    "{}: Fee was changed externally {} -> {} ppm",
+                    id, last_fee, current_fee
+                );
+                store_external_change(db, channel_id, last_fee, current_fee);
+                let mut decision = Decision {
+                    channel_id: channel_id.clone(),
+                    short_channel_id: id.clone(),
+                    time: Utc::now().timestamp(),
+                    aggregates: None,
+                    reason: format!("Fee was changed externally from {} ppm", last_fee),
+                    proposed_fee: None,
+                    applied_fee: Some(current_fee),
+                    success: Some(true),
+                };
+                match cli.external_fee_policy {
+                    ExternalFeePolicy::Adopt => {
+                        info!("{}: Adopting external fee {} ppm", id, current_fee);
+                        decision.reason += ". Adopting it";
+                    }
+                    ExternalFeePolicy::Revert => {
+                        info!(
+                            "{}: Reverting external fee {} -> {} ppm",
+                            id, current_fee, last_fee
+                        );
+                        let success = set_channel_fee(client, &id, last_fee).await;
+                        if success {
+                            FEE_CHANGES.with_label_values(&[&cli.node_name]).inc();
+                            current_fee = last_fee;
+                        }
+                        decision.reason += ". Reverting it";
+                        decision.proposed_fee = Some(last_fee);
+                        decision.applied_fee = Some(last_fee);
+                        decision.success = Some(success);
+                    }
+                    ExternalFeePolicy::Pause => {
+                        info!(
+                            "{}: Pausing management at external fee {} ppm",
+                            id, current_fee
+                        );
+                        let paused = Pin {
+                            channel_id: channel_id.clone(),
+                            short_channel_id: id.clone(),
+                            fee: current_fee,
+                            until: None,
+                            pinned: Utc::now().timestamp(),
+                        };
+                        store_pin(db, &paused);
+                        decision.reason += ". Pausing management until unpinned";
+                        pin = Some(paused);
+                    }
+                }
+                store_decision(db, &decision, decisions);
+            }
+        }
+        if let Some(pin) = &pin {
+            if current_fee != pin.fee {
+                info!(
+                    "{}: Restoring pinned fee {} -> {} ppm",
+                    id, current_fee, pin.fee
+                );
+                let success = set_channel_fee(client, &id, pin.fee).await;
+                if success {
+                    FEE_CHANGES.with_label_values(&[&cli.node_name]).inc();
+                }
+                store_decision(
+                    db,
+                    &Decision {
+                        channel_id: channel_id.clone(),
+                        short_channel_id: id.clone(),
+                        time: Utc::now().timestamp(),
+                        aggregates: None,
+                        reason: pin_reason(pin),
+                        proposed_fee: Some(pin.fee),
+                        applied_fee: Some(pin.fee),
+                        success: Some(success),
+                    },
+                    decisions,
+                );
+                current_fee = pin.fee;
+            }
+        }
 
-        let current_revenue = get_revenue_since(
-            last_updated,
-            ShortChannelId::from_str(id.as_str()).unwrap(),
-            client,
-        )
-        .await;
+        if cli.warmup_policy != WarmupPolicy::None
+            && has_history
+            && pin.is_none()
+            && excluded.is_none()
+            && query_epoch_count(channel_id, db) == 0
+            && !query_has_applied_decision(channel_id, WARMUP_REASON, db)
+        {
+            let other_fees: Vec<u32> = managed_fees
+                .iter()
+                .filter(|(other, _)| *other != id)
+                .map(|(_, fee)| *fee)
+                .collect();
+            let (fee, reason) =
+                warmup_fee(cli, &channel.peer_id, &other_fees, node_id.as_ref(), client).await;
+            info!("{}: {} {} -> {} ppm", id, reason, current_fee, fee);
+            let success = set_channel_fee(client, &id, fee).await;
+            if success && fee != current_fee {
+                FEE_CHANGES.with_label_values(&[&cli.node_name]).inc();
+            }
+            store_decision(
+                db,
+                &Decision {
+                    channel_id: channel_id.clone(),
+                    short_channel_id: id.clone(),
+                    time: Utc::now().timestamp(),
+                    aggregates: None,
+                    reason,
+                    proposed_fee: Some(fee),
+                    applied_fee: Some(fee),
+                    success: Some(success),
+                },
+                decisions,
+            );
+            // The history starts in the next iteration at the warmup fee
+            continue;
+        }
+
+        if cli.coordinate_peers && pin.is_none() && excluded.is_none() {
+            trace!("{}: Coordinating fee with peer {}", id, channel.peer_id);
+            coordinated
+                .entry(channel.peer_id.clone())
+                .or_default()
+                .push(CoordinatedChannel {
+                    id,
+                    channel,
+                    current_fee,
+                });
+            continue;
+        }
+
+        let last_values = query_last_channel_values(channel_id, cli.epochs, db);
+        trace!("{}: Queried last channel values", id);
+
+        let now = Utc::now().timestamp();
+        let epoch = Epoch::current(cli, channel_id, &last_values, now, db);
+        if !last_values.is_empty() && epoch.end > now {
+            trace!(
+                "{}: Skipped iteration because current epoch is still ongoing",
+                id
+            );
+            continue;
+        }
+
+        let Forwards {
+            revenue: current_revenue,
+            count: forward_count,
+        } = match epoch.forwards(cli, &id, now, client).await {
+            Ok(forwards) => forwards,
+            Err(e) => {
+                error!(
+                    "{}: Skipped channel because its forwards are unknown: {:#}",
+                    id, e
+                );
+                continue;
+            }
+        };
+        CHANNEL_EPOCH_REVENUE
+            .with_label_values(&[&cli.node_name, &id])
+            .set(current_revenue.try_into().unwrap_or(i64::MAX));
+        CHANNEL_EPOCH_FORWARDS
+            .with_label_values(&[&cli.node_name, &id])
+            .set(forward_count.try_into().unwrap_or(i64::MAX));
         debug!(
             "{}: Current[fee: {}, revenue: {}, last_updated: {}]",
-            id, current_fee, current_revenue, last_updated
+            id, current_fee, current_revenue, epoch.start
         );
 
-        let mut values: Vec<(u32, u32)> = last_values
+        let mut values: Vec<(u32, u64)> = last_values
             .iter()
             .map(|(_, fee, revenue)| (*fee, *revenue))
             .collect();
-        values.insert(0, (current_fee, current_revenue.try_into().unwrap()));
-        if let Some(new_fee) = NewFees::calculate(&values, adjustment_divisor, &id)
+        values.insert(0, (current_fee, current_revenue));
+        let peer_is_flaky = peer_is_flaky(
+            &channel.peer_id,
+            cli.epochs * cli.epoch_length,
+            cli.flaky_threshold,
+            db,
+        );
+        let mut decision = Decision {
+            channel_id: channel_id.clone(),
+            short_channel_id: id.clone(),
+            time: now,
+            aggregates: None,
+            reason: String::from("No last values"),
+            proposed_fee: None,
+            applied_fee: None,
+            success: None,
+        };
+        if let Some(pin) = &pin {
+            debug!("{}: Skipped fee calculation because fee is pinned", id);
+            decision.reason = pin_reason(pin);
+        } else if let Some(excluded) = excluded {
+            debug!("{}: Skipped fee calculation. {}", id, excluded);
+            decision.reason = excluded;
+        } else if let Some(mut new_fees) =
+            NewFees::new(&values, cli.adjustment_divisor, peer_is_flaky, &id)
         {
-            info!("{}: New fee {} -> {} msats", id, current_fee, new_fee);
-            set_channel_fee(client, &id, new_fee).await;
+            let proposed_fee = new_fees.determine();
+            decision.reason = new_fees.reason();
+            let (new_fee, bounded) = bound_by_competitors(
+                cli,
+                proposed_fee,
+                &channel.peer_id,
+                node_id.as_ref(),
+                client,
+            )
+            .await;
+            if let Some(bounded) = bounded {
+                info!("{}: {} -> {} ppm", id, bounded, new_fee);
+                decision.reason = format!("{}, {}", decision.reason, bounded);
+            }
+            info!("{}: New fee {} -> {} ppm", id, current_fee, new_fee);
+            let success = set_channel_fee(client, &id, new_fee).await;
+            if success && new_fee != current_fee {
+                FEE_CHANGES.with_label_values(&[&cli.node_name]).inc();
+            }
+            decision.aggregates = Some(new_fees.aggregates());
+            decision.proposed_fee = Some(proposed_fee);
+            decision.applied_fee = Some(new_fee);
+            decision.success = Some(success);
+        }
+        store_decision(db, &decision, decisions);
+        store_current_values(db, channel_id, id, current_fee, current_revenue);
+    }
+    for (peer_id, mut channels) in coordinated {
+        channels.sort_by(|a, b| a.id.cmp(&b.id));
+        iterate_peer(
+            cli,
+            &peer_id,
+            channels,
+            node_id.as_ref(),
+            client,
+            db,
+            decisions,
+        )
+        .await?;
+    }
+    if let Some(days) = cli.retention_days {
+        let archive = cli.data_dir.join("archive.sqlite");
+        let archive = match cli.retention_action {
+            RetentionAction::Archive => Some(archive.as_path()),
+            RetentionAction::Delete => None,
+        };
+        let before = Utc::now().timestamp() - i64::from(days) * 24 * 60 * 60;
+        prune_closed_channels(before, &open_peers, archive, db);
+    }
+    Ok(())
+}
+/// The epoch of a channel that is currently ongoing
+struct Epoch {
+    /// When the values of the last epoch were stored
+    start: i64,
+    /// When the epoch is over and new fees are calculated
+    end: i64,
+    /// How long the peer was offline since the start of the epoch
+    offline_seconds: i64,
+}
+impl Epoch {
+    fn current(
+        cli: &Cli,
+        channel_id: &String,
+        last_values: &[(i64, u32, u64)],
+        now: i64,
+        db: &mut Connection,
+    ) -> Self {
+        let epoch_duration = Duration::hours(cli.epoch_length.into()).num_seconds();
+        let start = if let Some((last_updated, _, _)) = last_values.first() {
+            *last_updated
+        } else {
+            now - epoch_duration
+        };
+        let offline_seconds = query_offline_seconds_since(channel_id, start, db);
+        let end = if cli.offline_policy == OfflinePolicy::Hold {
+            start + epoch_duration + offline_seconds
+        } else {
+            start + epoch_duration
+        };
+        Self {
+            start,
+            end,
+            offline_seconds,
+        }
+    }
+    /// The forwards out of the channel since the start of the epoch
+    async fn forwards(
+        &self,
+        cli: &Cli,
+        id: &String,
+        now: i64,
+        client: &mut Client,
+    ) -> Result<Forwards> {
+        let mut forwards = get_forwards_since(
+            self.start,
+            ShortChannelId::from_str(id.as_str()).unwrap(),
+            client,
+        )
+        .await?;
+        if cli.offline_policy != OfflinePolicy::Hold && self.offline_seconds > 0 {
+            forwards.revenue = scale_to_online_time(
+                forwards.revenue,
+                Duration::hours(cli.epoch_length.into()).num_seconds(),
+                now - self.start,
+                self.offline_seconds,
+            );
+            debug!(
+                "{}: Peer was offline for {} minutes during epoch. Scaled revenue to {} msats",
+                id,
+                self.offline_seconds / 60,
+                forwards.revenue
+            );
         }
-        store_current_values(db, id, current_fee, current_revenue as u32);
+        Ok(forwards)
     }
 }
+/// Extrapolates revenue that was earned while the peer was only partially online to the length
+/// of an epoch, so that offline gaps don't distort the revenue of an epoch. Epochs that span more
+/// than their length because of offline iterations are only scaled to their length, and revenue
+/// is never scaled down.
+fn scale_to_online_time(
+    revenue: u64,
+    epoch_duration: i64,
+    duration: i64,
+    offline_seconds: i64,
+) -> u64 {
+    let online_seconds = duration - offline_seconds;
+    let scaled_seconds = duration.min(epoch_duration);
+    if online_seconds <= 0 || online_seconds >= scaled_seconds {
+        return revenue;
+    }
+    (revenue as u128 * scaled_seconds as u128 / online_seconds as u128) as u64
+}
 #[derive(Clone, Debug)]
 struct NewFees<'a> {
-    past_revenue: u32,
-    average_revenue: u32,
-    present_revenue: u32,
-    current_revenue: u32,
+    past_revenue: u64,
+    average_revenue: u64,
+    present_revenue: u64,
+    current_revenue: u64,
     past_fee: u32,
     average_fee: u32,
     present_fee: u32,
     current_fee: u32,
     adjustment_fee: u32,
+    peer_is_flaky: bool,
+    reasons: Vec<&'static str>,
     id: &'a String,
 }
 impl<'a> NewFees<'a> {
-    pub fn calculate(
-        values: &Vec<(u32, u32)>,
+    /// Aggregates the values of the past epochs. Returns `None` if there are no past values.
+    pub fn new(
+        values: &[(u32, u64)],
         adjustment_divisor: u32,
+        peer_is_flaky: bool,
         id: &'a String,
-    ) -> Option<u32> {
+    ) -> Option<Self> {
         if values.len() < 2 {
             debug!("{}: No last values -> No new fee", id);
             return None;
@@ -202,29 +1242,33 @@ impl<'a> NewFees<'a> {
             present_fee: 0,
             current_fee: 0,
             adjustment_fee: 0,
+            peer_is_flaky,
+            reasons: vec![],
             id,
         };
+        // Fees are summed up as u64 so that many epochs of high fees can't overflow
+        let (mut present_fee, mut past_fee, mut average_fee): (u64, u64, u64) = (0, 0, 0);
         let (mut first_n, mut last_n) = (0, 0);
         for (i, (fee, revenue)) in values.iter().enumerate() {
             if i <= (values.len() - 1) / 3 {
-                p.present_fee += *fee;
-                p.present_revenue += *revenue;
+                present_fee += u64::from(*fee);
+                p.present_revenue = p.present_revenue.saturating_add(*revenue);
                 first_n += 1;
             }
             if i >= 2 * values.len() / 3 {
-                p.past_fee += *fee;
-                p.past_revenue += *revenue;
+                past_fee += u64::from(*fee);
+                p.past_revenue = p.past_revenue.saturating_add(*revenue);
                 last_n += 1;
             }
-            p.average_fee += *fee;
-            p.average_revenue += *revenue;
+            average_fee += u64::from(*fee);
+            p.average_revenue = p.average_revenue.saturating_add(*revenue);
         }
-        p.present_fee /= first_n;
+        p.present_fee = average_of(present_fee, first_n);
         p.present_revenue /= first_n;
-        p.past_fee /= last_n;
+        p.past_fee = average_of(past_fee, last_n);
         p.past_revenue /= last_n;
-        p.average_fee /= values.len() as u32;
-        p.average_revenue /= values.len() as u32;
+        p.average_fee = average_of(average_fee, values.len() as u64);
+        p.average_revenue /= values.len() as u64;
 
         let (current_fee, current_revenue) = *values.first().unwrap();
         p.current_fee = current_fee;
@@ -236,81 +1280,101 @@ impl<'a> NewFees<'a> {
             1
         };
         debug!("{}: {}", id, p);
-
-        p.determine()
+        Some(p)
+    }
+    /// The reasoning behind the last determined fee
+    pub fn reason(&self) -> String {
+        self.reasons.join(", ")
+    }
+    pub fn aggregates(&self) -> Aggregates {
+        Aggregates {
+            past_revenue: self.past_revenue,
+            average_revenue: self.average_revenue,
+            present_revenue: self.present_revenue,
+            current_revenue: self.current_revenue,
+            past_fee: self.past_fee,
+            average_fee: self.average_fee,
+            present_fee: self.present_fee,
+            current_fee: self.current_fee,
+        }
+    }
+    fn note(&mut self, reason: &'static str) {
+        debug!("{}: {}", self.id, reason);
+        self.reasons.push(reason);
     }
     #[allow(clippy::if_same_then_else)]
-    fn determine(&self) -> Option<u32> {
+    pub fn determine(&mut self) -> u32 {
+        self.reasons.clear();
         let new_fee: u32 = if self.average_revenue == 0 {
-            debug!("{}: Halving fee to search for revenue", self.id);
+            self.note("Halving fee to search for revenue");
             self.current_fee / 2
         } else if self.present_revenue == 0 {
-            debug!("{}: No present revenue", self.id);
+            self.note("No present revenue");
             self.decrease(true)
         } else if self.rev_is_rising() {
-            debug!("{}: Revenue is rising", self.id);
+            self.note("Revenue is rising");
             if self.fee_is_rising() {
-                debug!("{}: Fee is rising", self.id);
+                self.note("Fee is rising");
                 self.increase(true)
             } else if self.fee_is_falling() {
-                debug!("{}: Fee is falling", self.id);
-                self.average_fee
+                self.note("Fee is falling");
+                self.average()
             } else if self.fee_has_higher_average() {
-                debug!("{}: Fee has higher average", self.id);
-                self.average_fee
+                self.note("Fee has higher average");
+                self.average()
             } else if self.fee_has_lower_average() {
-                debug!("{}: Fee has lower average", self.id);
+                self.note("Fee has lower average");
                 self.increase(false)
             } else {
                 self.increase(true)
             }
         } else if self.rev_is_falling() {
-            debug!("{}: Revenue is falling", self.id);
+            self.note("Revenue is falling");
             if self.fee_is_rising() {
-                debug!("{}: Fee is rising", self.id);
+                self.note("Fee is rising");
                 self.decrease(true)
             } else if self.fee_is_falling() {
-                debug!("{}: Fee is falling", self.id);
+                self.note("Fee is falling");
                 self.increase(true)
             } else if self.fee_has_higher_average() {
-                debug!("{}: Fee has higher average", self.id);
+                self.note("Fee has higher average");
                 self.increase(false)
             } else if self.fee_has_lower_average() {
-                debug!("{}: Fee has lower average", self.id);
-                self.average_fee
+                self.note("Fee has lower average");
+                self.average()
             } else {
                 self.decrease(false)
             }
         } else if self.rev_has_higher_average() {
-            debug!("{}: Revenue has higher average", self.id);
+            self.note("Revenue has higher average");
             if self.fee_is_rising() {
-                debug!("{}: Fee is rising", self.id);
-                self.average_fee
+                self.note("Fee is rising");
+                self.average()
             } else if self.fee_is_falling() {
-                debug!("{}: Fee is falling", self.id);
+                self.note("Fee is falling");
                 self.increase(false)
             } else if self.fee_has_higher_average() {
-                debug!("{}: Fee has higher average", self.id);
+                self.note("Fee has higher average");
                 self.increase(true)
             } else if self.fee_has_lower_average() {
-                debug!("{}: Fee has lower average", self.id);
+                self.note("Fee has lower average");
                 self.decrease(true)
             } else {
-                self.increase(false) 
+                self.increase(false)
             }
         } else if self.rev_has_lower_average() {
-            debug!("{}: Revenue has lower average", self.id);
+            self.note("Revenue has lower average");
             if self.fee_is_rising() {
-                debug!("{}: Fee is rising", self.id);
+                self.note("Fee is rising");
                 self.increase(false)
             } else if self.fee_is_falling() {
-                debug!("{}: Fee is falling", self.id);
-                self.average_fee
+                self.note("Fee is falling");
+                self.average()
             } else if self.fee_has_higher_average() {
-                debug!("{}: Fee has higher average", self.id);
+                self.note("Fee has higher average");
                 self.decrease(true)
             } else if self.fee_has_lower_average() {
-                debug!("{}: Fee has lower average", self.id);
+                self.note("Fee has lower average");
                 self.increase(true)
             } else {
                 self.increase(false)
@@ -319,10 +1383,42 @@ impl<'a> NewFees<'a> {
             self.increase(false)
         };
 
+        if self.peer_is_flaky && new_fee < self.current_fee {
+            self.note("Not lowering fee because peer is mostly offline");
+            return self.current_fee;
+        }
         if new_fee == 0 {
-            return Some(1);
+            return 1;
+        }
+        new_fee
+    }
+    /// Classifies how revenue developed from the past over the average to the present
+    pub fn revenue_trend(&self) -> &'static str {
+        if self.rev_is_rising() {
+            "rising"
+        } else if self.rev_is_falling() {
+            "falling"
+        } else if self.rev_has_higher_average() {
+            "higher average"
+        } else if self.rev_has_lower_average() {
+            "lower average"
+        } else {
+            "flat"
+        }
+    }
+    /// Classifies how the fee developed from the past over the average to the present
+    pub fn fee_trend(&self) -> &'static str {
+        if self.fee_is_rising() {
+            "rising"
+        } else if self.fee_is_falling() {
+            "falling"
+        } else if self.fee_has_higher_average() {
+            "higher average"
+        } else if self.fee_has_lower_average() {
+            "lower average"
+        } else {
+            "flat"
         }
-        Some(new_fee)
     }
     fn rev_is_rising(&self) -> bool {
         self.past_revenue < self.average_revenue && self.average_revenue < self.present_revenue
@@ -348,40 +1444,58 @@ impl<'a> NewFees<'a> {
     fn fee_has_higher_average(&self) -> bool {
         self.past_fee < self.average_fee && self.average_fee > self.present_fee
     }
-    fn increase(&self, fast: bool) -> u32 {
+    fn increase(&mut self, fast: bool) -> u32 {
         if fast {
-            debug!("{}: Increasing fee fast", self.id);
-            self.current_fee
-                .saturating_add(self.current_fee.abs_diff(self.present_fee) + self.adjustment_fee * 2)
+            self.note("Increasing fee fast");
+            self.current_fee.saturating_add(
+                self.current_fee
+                    .abs_diff(self.present_fee)
+                    .saturating_add(self.adjustment_fee.saturating_mul(2)),
+            )
         } else {
-            debug!("{}: Increasing fee", self.id);
+            self.note("Increasing fee");
             self.current_fee.saturating_add(self.adjustment_fee)
         }
     }
-    fn decrease(&self, fast: bool) -> u32 {
+    fn average(&mut self) -> u32 {
+        self.note("Returning to average fee");
+        self.average_fee
+    }
+    fn decrease(&mut self, fast: bool) -> u32 {
         if fast {
-            debug!("{}: Decreasing fee fast", self.id);
-            self.current_fee
-                .saturating_sub(self.current_fee.abs_diff(self.present_fee) + self.adjustment_fee * 2)
+            self.note("Decreasing fee fast");
+            self.current_fee.saturating_sub(
+                self.current_fee
+                    .abs_diff(self.present_fee)
+                    .saturating_add(self.adjustment_fee.saturating_mul(2)),
+            )
         } else {
-            debug!("{}: Decreasing fee", self.id);
+            self.note("Decreasing fee");
             self.current_fee.saturating_sub(self.adjustment_fee)
         }
     }
 }
+/// Averages fees that were summed up as u64. The average of u32 fees always fits into a u32.
+fn average_of(fee_sum: u64, n: u64) -> u32 {
+    u32::try_from(fee_sum / n).expect("Average fee doesn't fit into u32")
+}
 impl<'a> Display for NewFees<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "NewFee{{revenue: {}->{}->{}({}), fee: {}->{}->{}({}), channel: {}}}",
+            "NewFee{{revenue: {}->{}->{}({}) {}, fee: {}->{}->{}({}) {}, adjustment: {}, flaky: {}, channel: {}}}",
             self.past_revenue,
             self.average_revenue,
             self.present_revenue,
             self.current_revenue,
+            self.revenue_trend(),
             self.past_fee,
             self.average_fee,
             self.present_fee,
             self.current_fee,
+            self.fee_trend(),
+            self.adjustment_fee,
+            self.peer_is_flaky,
             self.id
         )
     }
@@ -485,4 +1599,116 @@ mod tests {
         let fee = new_fee(values.clone(), fee, 80, 10).await.unwrap();
         assert_eq!(fee, 354);
     }
+    #[test]
+    fn calculate_with_revenue_beyond_u32() {
+        let id = String::from("1x2x3");
+        let values = vec![(u32::MAX, 5_000_000_000); 6];
+        let mut new_fees = NewFees::new(&values, 10, false, &id).unwrap();
+        assert_eq!(new_fees.determine(), u32::MAX);
+        assert_eq!(new_fees.reason(), "Increasing fee");
+        assert_eq!(
+            new_fees.aggregates(),
+            Aggregates {
+                past_revenue: 5_000_000_000,
+                average_revenue: 5_000_000_000,
+                present_revenue: 5_000_000_000,
+                current_revenue: 5_000_000_000,
+                past_fee: u32::MAX,
+                average_fee: u32::MAX,
+                present_fee: u32::MAX,
+                current_fee: u32::MAX,
+            }
+        );
+        let values = vec![
+            (1000, 6_000_000_000),
+            (1000, 6_000_000_000),
+            (900, 5_000_000_000),
+            (900, 5_000_000_000),
+            (800, 5_000_000_000),
+            (800, 5_000_000_000),
+        ];
+        let mut new_fees = NewFees::new(&values, 10, false, &id).unwrap();
+        assert_eq!(new_fees.determine(), 1200);
+        assert_eq!(
+            new_fees.reason(),
+            "Revenue is rising, Fee is rising, Increasing fee fast"
+        );
+        assert_eq!(
+            new_fees.aggregates(),
+            Aggregates {
+                past_revenue: 5_000_000_000,
+                average_revenue: 5_333_333_333,
+                present_revenue: 6_000_000_000,
+                current_revenue: 6_000_000_000,
+                past_fee: 800,
+                average_fee: 900,
+                present_fee: 1000,
+                current_fee: 1000,
+            }
+        );
+    }
+    #[test]
+    fn adjust_fast_without_overflowing() {
+        let id = String::from("1x2x3");
+        let values = vec![
+            (4_000_000_000, 1000),
+            (500_000_000, 1000),
+            (500_000_000, 1000),
+        ];
+        let mut new_fees = NewFees::new(&values, 10, false, &id).unwrap();
+        // The difference to the present fee and the adjustment don't fit into a u32 together
+        new_fees.present_fee = 500_000_000;
+        assert_eq!(new_fees.adjustment_fee, 400_000_000);
+        assert_eq!(new_fees.increase(true), u32::MAX);
+        assert_eq!(new_fees.decrease(true), 0);
+        new_fees.present_fee = 3_900_000_000;
+        assert_eq!(new_fees.increase(true), u32::MAX);
+        assert_eq!(new_fees.decrease(true), 3_100_000_000);
+    }
+    #[test]
+    fn keep_fee_of_flaky_peer() {
+        let id = String::from("1x2x3");
+        let values = vec![(500, 0); 6];
+        let mut new_fees = NewFees::new(&values, 10, false, &id).unwrap();
+        assert_eq!(new_fees.determine(), 250);
+        let mut new_fees = NewFees::new(&values, 10, true, &id).unwrap();
+        assert_eq!(new_fees.determine(), 500);
+        assert_eq!(
+            new_fees.reason(),
+            "Halving fee to search for revenue, Not lowering fee because peer is mostly offline"
+        );
+        let values = vec![(500, 0), (500, 0), (500, 1000), (500, 1000)];
+        let mut new_fees = NewFees::new(&values, 10, true, &id).unwrap();
+        assert_eq!(new_fees.determine(), 500);
+        assert_eq!(
+            new_fees.reason(),
+            "No present revenue, Decreasing fee fast, Not lowering fee because peer is mostly offline"
+        );
+    }
+    #[test]
+    fn parse_dates_and_timestamps() {
+        assert_eq!(parse_time("2022-09-01"), Ok(1661990400));
+        assert_eq!(parse_time("2022-09-01T12:00:00Z"), Ok(1662033600));
+        assert!(parse_time("yesterday").is_err());
+    }
+    #[test]
+    fn scale_revenue_to_online_time() {
+        assert_eq!(scale_to_online_time(1000, 3600, 3600, 0), 1000);
+        assert_eq!(scale_to_online_time(1000, 3600, 3600, 1800), 2000);
+        assert_eq!(scale_to_online_time(1000, 3600, 3600, 3600), 1000);
+        // Offline for 48h of an epoch of 24h that spanned 58h because of the gap
+        let hour = 3600;
+        assert_eq!(
+            scale_to_online_time(1000, 24 * hour, 58 * hour, 48 * hour),
+            2400
+        );
+        assert_eq!(
+            scale_to_online_time(1000, 10 * hour, 58 * hour, 48 * hour),
+            1000
+        );
+        assert_eq!(
+            scale_to_online_time(1000, 8 * hour, 58 * hour, 48 * hour),
+            1000
+        );
+    }
 }
@@ -0,0 +1,128 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use log::{error, info};
+use prometheus::{
+    register_gauge_vec, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
+    Encoder, GaugeVec, HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+lazy_static! {
+    pub static ref CHANNEL_FEE: IntGaugeVec = register_int_gauge_vec!(
+        "cln_feeder_channel_fee_ppm",
+        "The current proportional fee of the channel",
+        &["node", "channel"]
+    )
+    .unwrap();
+    pub static ref CHANNEL_EPOCH_REVENUE: IntGaugeVec = register_int_gauge_vec!(
+        "cln_feeder_channel_epoch_revenue_msat",
+        "The revenue of the last completed epoch of the channel",
+        &["node", "channel"]
+    )
+    .unwrap();
+    pub static ref CHANNEL_EPOCH_FORWARDS: IntGaugeVec = register_int_gauge_vec!(
+        "cln_feeder_channel_epoch_forwards",
+        "The number of settled forwards in the last completed epoch of the channel",
+        &["node", "channel"]
+    )
+    .unwrap();
+    pub static ref CHANNEL_BALANCE_RATIO: GaugeVec = register_gauge_vec!(
+        "cln_feeder_channel_balance_ratio",
+        "The share of the channel capacity that is on our side",
+        &["node", "channel"]
+    )
+    .unwrap();
+    pub static ref FEE_CHANGES: IntCounterVec = register_int_counter_vec!(
+        "cln_feeder_fee_changes_total",
+        "The number of fee changes that were set on the node",
+        &["node"]
+    )
+    .unwrap();
+    pub static ref RPC_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "cln_feeder_rpc_errors_total",
+        "The number of failed calls to CLN",
+        &["node"]
+    )
+    .unwrap();
+    pub static ref ITERATION_DURATION: HistogramVec = register_histogram_vec!(
+        "cln_feeder_iteration_duration_seconds",
+        "How long an iteration over all channels took",
+        &["node"]
+    )
+    .unwrap();
+    pub static ref LAST_SUCCESSFUL_ITERATION: IntGaugeVec = register_int_gauge_vec!(
+        "cln_feeder_last_successful_iteration_timestamp_seconds",
+        "When the last iteration that didn't fail finished",
+        &["node"]
+    )
+    .unwrap();
+}
+
+async fn handle(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("Couldn't encode metrics");
+    Ok(Response::builder()
+        .header(hyper::header::CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap())
+}
+/// Serves the metrics for Prometheus on `/metrics`
+pub async fn serve_metrics(address: SocketAddr) {
+    info!("Serving metrics on http://{}/metrics", address);
+    let service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+    if let Err(e) = Server::bind(&address).serve(service).await {
+        error!("Metrics server failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[tokio::test]
+    async fn scrape_iteration_and_channel_metrics() {
+        LAST_SUCCESSFUL_ITERATION
+            .with_label_values(&["scraped"])
+            .set(1660000000);
+        CHANNEL_FEE
+            .with_label_values(&["scraped", "1x2x3"])
+            .set(100);
+        CHANNEL_EPOCH_REVENUE
+            .with_label_values(&["scraped", "1x2x3"])
+            .set(2000);
+        CHANNEL_EPOCH_FORWARDS
+            .with_label_values(&["scraped", "1x2x3"])
+            .set(3);
+        CHANNEL_BALANCE_RATIO
+            .with_label_values(&["scraped", "1x2x3"])
+            .set(0.25);
+
+        let request = Request::get("/metrics").body(Body::empty()).unwrap();
+        let response = handle(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        for line in [
+            "cln_feeder_last_successful_iteration_timestamp_seconds{node=\"scraped\"} 1660000000",
+            "cln_feeder_channel_fee_ppm{channel=\"1x2x3\",node=\"scraped\"} 100",
+            "cln_feeder_channel_epoch_revenue_msat{channel=\"1x2x3\",node=\"scraped\"} 2000",
+            "cln_feeder_channel_epoch_forwards{channel=\"1x2x3\",node=\"scraped\"} 3",
+            "cln_feeder_channel_balance_ratio{channel=\"1x2x3\",node=\"scraped\"} 0.25",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} not in\n{}", line, text);
+        }
+
+        let request = Request::get("/other").body(Body::empty()).unwrap();
+        let response = handle(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,271 @@
+use chrono::Utc;
+use log::{info, trace};
+use rusqlite::{Connection, Transaction};
+
+type Migration = fn(&Transaction) -> rusqlite::Result<()>;
+
+/// The migrations of the database schema in the order they are applied. Each one is applied
+/// exactly once in its own transaction and its position is its version. Released migrations
+/// must not be changed. Changes to the schema are appended as new migrations.
+const MIGRATIONS: &[(&str, Migration)] = &[
+    ("Create channels table", create_channels),
+    ("Key channels on channel id", key_channels_on_channel_id),
+    (
+        "Create offline intervals and peer uptime tables",
+        create_offline_intervals_and_peer_uptime,
+    ),
+    ("Add types and constraints to channels", type_channels),
+    ("Create decisions table", create_decisions),
+    ("Create pins table", create_pins),
+    ("Create peers table", create_peers),
+    ("Track external fee changes", track_external_changes),
+    ("Create closed channels table", create_closed_channels),
+    ("Create metadata table", create_metadata),
+    ("Give decisions their own id", identify_decisions),
+];
+
+/// Brings the schema of the database to the latest version.
+pub fn migrate(db: &mut Connection) {
+    db.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version \
+    (version INTEGER PRIMARY KEY, \
+    applied INTEGER NOT NULL)",
+        (),
+    )
+    .expect("Couldn't create schema version table");
+    let current = schema_version(db);
+    trace!("Database is at schema version {}", current);
+    for (version, (description, migration)) in MIGRATIONS.iter().enumerate() {
+        let version = version as u32 + 1;
+        if version <= current {
+            continue;
+        }
+        info!("Migrating database to version {}: {}", version, description);
+        let tx = db.transaction().expect("Couldn't start migration");
+        migration(&tx).unwrap_or_else(|e| panic!("Migration to version {} failed: {}", version, e));
+        tx.execute(
+            "INSERT INTO schema_version (version, applied) VALUES (?1, ?2)",
+            (version, Utc::now().timestamp()),
+        )
+        .expect("Couldn't store schema version");
+        tx.commit().expect("Couldn't commit migration");
+    }
+}
+pub fn schema_version(db: &Connection) -> u32 {
+    db.query_row("SELECT MAX(version) FROM schema_version", [], |row| {
+        row.get::<_, Option<u32>>(0)
+    })
+    .expect("Couldn't query schema version")
+    .unwrap_or(0)
+}
+fn has_column(db: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let mut statement = db.prepare(&format!("PRAGMA table_info({})", table))?;
+    let mut names = statement.query_map([], |row| row.get::<_, String>("name"))?;
+    names.try_fold(false, |found, name| Ok(found || name? == column))
+}
+
+/// The table as it was created before the schema was versioned
+fn create_channels(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS channels \
+    (short_channel_id NON NULL, \
+    last_fee NON NULL, \
+    last_revenue NON NULL, \
+    last_updated NON NULL, \
+    PRIMARY KEY (short_channel_id, last_updated))",
+    )
+}
+/// Existing rows only know the short channel id of their channel. It is used as a placeholder
+/// channel id until [`crate::db::link_channel_id`] sees the channel on the node.
+fn key_channels_on_channel_id(tx: &Transaction) -> rusqlite::Result<()> {
+    if has_column(tx, "channels", "channel_id")? {
+        return Ok(());
+    }
+    tx.execute_batch(
+        "ALTER TABLE channels RENAME TO channels_by_short_channel_id; \
+        CREATE TABLE channels \
+            (channel_id NON NULL, \
+            short_channel_id NON NULL, \
+            last_fee NON NULL, \
+            last_revenue NON NULL, \
+            last_updated NON NULL, \
+            PRIMARY KEY (channel_id, last_updated)); \
+        INSERT INTO channels \
+            (channel_id, short_channel_id, last_fee, last_revenue, last_updated) \
+            SELECT short_channel_id, short_channel_id, last_fee, last_revenue, last_updated \
+            FROM channels_by_short_channel_id; \
+        DROP TABLE channels_by_short_channel_id;",
+    )
+}
+fn create_offline_intervals_and_peer_uptime(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS offline_intervals \
+    (channel_id TEXT NOT NULL, \
+    went_offline INTEGER NOT NULL, \
+    came_online INTEGER, \
+    fee_before INTEGER, \
+    PRIMARY KEY (channel_id, went_offline)); \
+    CREATE TABLE IF NOT EXISTS peer_uptime \
+    (peer_id TEXT NOT NULL, \
+    observed INTEGER NOT NULL, \
+    connected INTEGER NOT NULL, \
+    PRIMARY KEY (peer_id, observed));",
+    )?;
+    if has_column(tx, "offline_intervals", "short_channel_id")? {
+        tx.execute_batch(
+            "ALTER TABLE offline_intervals RENAME COLUMN short_channel_id TO channel_id",
+        )?;
+    }
+    Ok(())
+}
+/// The columns of the channels table had no types and `NON NULL` instead of `NOT NULL`, which
+/// SQLite accepted as a type name without a constraint.
+fn type_channels(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE channels RENAME TO channels_untyped; \
+        CREATE TABLE channels \
+            (channel_id TEXT NOT NULL, \
+            short_channel_id TEXT NOT NULL, \
+            last_fee INTEGER NOT NULL, \
+            last_revenue INTEGER NOT NULL, \
+            last_updated INTEGER NOT NULL, \
+            PRIMARY KEY (channel_id, last_updated)); \
+        INSERT INTO channels \
+            (channel_id, short_channel_id, last_fee, last_revenue, last_updated) \
+            SELECT channel_id, short_channel_id, last_fee, last_revenue, last_updated \
+            FROM channels_untyped; \
+        DROP TABLE channels_untyped;",
+    )
+}
+fn create_decisions(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE decisions \
+    (channel_id TEXT NOT NULL, \
+    short_channel_id TEXT NOT NULL, \
+    time INTEGER NOT NULL, \
+    past_revenue INTEGER, \
+    average_revenue INTEGER, \
+    present_revenue INTEGER, \
+    current_revenue INTEGER, \
+    past_fee INTEGER, \
+    average_fee INTEGER, \
+    present_fee INTEGER, \
+    current_fee INTEGER, \
+    reason TEXT NOT NULL, \
+    proposed_fee INTEGER, \
+    applied_fee INTEGER, \
+    success INTEGER, \
+    PRIMARY KEY (channel_id, time))",
+    )
+}
+fn create_pins(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE pins \
+    (channel_id TEXT PRIMARY KEY, \
+    short_channel_id TEXT NOT NULL, \
+    fee INTEGER NOT NULL, \
+    until INTEGER, \
+    pinned INTEGER NOT NULL)",
+    )
+}
+/// The history of peers whose channels are coordinated to one fee
+fn create_peers(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE peers \
+    (peer_id TEXT NOT NULL, \
+    fee INTEGER NOT NULL, \
+    revenue INTEGER NOT NULL, \
+    time INTEGER NOT NULL, \
+    PRIMARY KEY (peer_id, time))",
+    )
+}
+/// Fee changes that weren't made by the feeder are logged and the epochs they happened in marked
+fn track_external_changes(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE channels ADD COLUMN externally_modified INTEGER NOT NULL DEFAULT 0; \
+        CREATE TABLE external_changes \
+            (channel_id TEXT NOT NULL, \
+            time INTEGER NOT NULL, \
+            expected_fee INTEGER NOT NULL, \
+            fee INTEGER NOT NULL, \
+            PRIMARY KEY (channel_id, time));",
+    )
+}
+fn create_closed_channels(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE closed_channels \
+    (channel_id TEXT PRIMARY KEY, \
+    short_channel_id TEXT NOT NULL, \
+    closed INTEGER NOT NULL)",
+    )
+}
+/// Facts about the database as a whole, like the node it belongs to
+fn create_metadata(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE metadata \
+    (key TEXT PRIMARY KEY, \
+    value TEXT NOT NULL)",
+    )
+}
+/// Decisions were keyed on their channel and second, so a second decision for a channel in the
+/// same second replaced the first one
+fn identify_decisions(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE decisions RENAME TO decisions_by_time; \
+        CREATE TABLE decisions \
+            (id INTEGER PRIMARY KEY AUTOINCREMENT, \
+            channel_id TEXT NOT NULL, \
+            short_channel_id TEXT NOT NULL, \
+            time INTEGER NOT NULL, \
+            past_revenue INTEGER, \
+            average_revenue INTEGER, \
+            present_revenue INTEGER, \
+            current_revenue INTEGER, \
+            past_fee INTEGER, \
+            average_fee INTEGER, \
+            present_fee INTEGER, \
+            current_fee INTEGER, \
+            reason TEXT NOT NULL, \
+            proposed_fee INTEGER, \
+            applied_fee INTEGER, \
+            success INTEGER); \
+        INSERT INTO decisions \
+            (channel_id, short_channel_id, time, \
+            past_revenue, average_revenue, present_revenue, current_revenue, \
+            past_fee, average_fee, present_fee, current_fee, \
+            reason, proposed_fee, applied_fee, success) \
+            SELECT channel_id, short_channel_id, time, \
+            past_revenue, average_revenue, present_revenue, current_revenue, \
+            past_fee, average_fee, present_fee, current_fee, \
+            reason, proposed_fee, applied_fee, success \
+            FROM decisions_by_time ORDER BY time; \
+        DROP TABLE decisions_by_time; \
+        CREATE INDEX decisions_by_channel ON decisions (channel_id, time);",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn migrate_empty_database() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db);
+        assert_eq!(schema_version(&db), MIGRATIONS.len() as u32);
+        assert!(has_column(&db, "channels", "channel_id").unwrap());
+        migrate(&mut db);
+        assert_eq!(schema_version(&db), MIGRATIONS.len() as u32);
+    }
+    #[test]
+    fn reject_null_values_after_migration() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db);
+        assert!(db
+            .execute(
+                "INSERT INTO channels (channel_id, short_channel_id, last_fee, last_updated) \
+                VALUES ('ab', '1x2x3', 100, 1660000000)",
+                (),
+            )
+            .is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use crate::Cli;
+use anyhow::{bail, Context, Result};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, ValueSource};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// A node in the nodes file. Its arguments override the options given on the command line, e.g.
+/// `{"name": "alpha", "socket": "/alpha/lightning-rpc", "args": ["--epochs", "3"]}`. Nodes
+/// without a socket are connected to over cln-grpc with the `--grpc-*` arguments.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeConfig {
+    pub name: String,
+    #[serde(default)]
+    pub socket: Option<PathBuf>,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// Reads the nodes file and returns the options of each node. Each node gets its own directory
+/// in the data directory unless its arguments set another one.
+pub fn load_nodes(cli: &Cli, path: &Path) -> Result<Vec<Cli>> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("Couldn't open nodes {:?}", path))?;
+    let nodes: Vec<NodeConfig> = serde_json::from_reader(std::io::BufReader::new(file))
+        .with_context(|| format!("Couldn't read nodes {:?}", path))?;
+    let mut names = HashSet::new();
+    for node in &nodes {
+        if node.name.is_empty() || !names.insert(&node.name) {
+            bail!("Node names must be unique and not empty: {:?}", node.name);
+        }
+    }
+    nodes.into_iter().map(|node| node_cli(cli, node)).collect()
+}
+/// Lists the options that a node can set for itself and takes the ones that it sets from its own
+/// arguments, so that all other options stay as given on the command line
+macro_rules! node_options {
+    ($($field:ident),* $(,)?) => {
+        #[cfg(test)]
+        const NODE_OPTIONS: &[&str] = &[$(stringify!($field)),*];
+        fn override_options(cli: &mut Cli, options: Cli, matches: &ArgMatches) {
+            $(
+                if matches.value_source(stringify!($field).replace('_', "-").as_str())
+                    == Some(ValueSource::CommandLine)
+                {
+                    cli.$field = options.$field;
+                }
+            )*
+        }
+    };
+}
+node_options!(
+    socket,
+    grpc_endpoint,
+    grpc_ca_cert,
+    grpc_client_cert,
+    grpc_client_key,
+    data_dir,
+    temp_database,
+    verbose,
+    log_filter,
+    adjustment_divisor,
+    epochs,
+    epoch_length,
+    offline_policy,
+    offline_fee,
+    external_fee_policy,
+    uptime_windows,
+    flaky_threshold,
+    metrics_listen,
+    metrics_allow_remote,
+    grpc_listen,
+    grpc_socket,
+    grpc_allow_remote,
+    http_listen,
+    http_allow_remote,
+    include_channels,
+    exclude_channels,
+    include_peers,
+    exclude_peers,
+    exclude_private,
+    min_capacity,
+    coordinate_peers,
+    competitor_ceiling,
+    competitor_floor,
+    warmup_policy,
+    warmup_fee,
+    warmup_percentile,
+    bootstrap,
+    retention_days,
+    retention_action,
+);
+fn node_cli(cli: &Cli, node: NodeConfig) -> Result<Cli> {
+    let socket = node
+        .socket
+        .map(|socket| vec!["--socket".into(), socket.to_string_lossy().to_string()]);
+    let matches = Cli::command()
+        .try_get_matches_from(
+            std::iter::once("cln-feeder".into())
+                .chain(socket.unwrap_or_default())
+                .chain(node.args),
+        )
+        .with_context(|| format!("Invalid arguments of node {}", node.name))?;
+    if let Some(subcommand) = matches.subcommand_name() {
+        bail!(
+            "Node {} can't set the subcommand {}. Subcommands are given on the command line",
+            node.name,
+            subcommand
+        );
+    }
+    let options = Cli::from_arg_matches(&matches)
+        .with_context(|| format!("Invalid arguments of node {}", node.name))?;
+    let mut node_cli = cli.clone();
+    override_options(&mut node_cli, options, &matches);
+    if matches.value_source("data-dir") != Some(ValueSource::CommandLine) {
+        node_cli.data_dir = cli.data_dir.join(&node.name);
+    }
+    node_cli.nodes = None;
+    node_cli.node_name = node.name;
+    Ok(node_cli)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Command;
+    use clap::Parser;
+    #[test]
+    fn override_options_per_node() {
+        let cli = Cli::parse_from([
+            "cln-feeder",
+            "--nodes",
+            "nodes.json",
+            "--epochs",
+            "4",
+            "--coordinate-peers",
+            "-vv",
+            "--exclude-peers",
+            "a,b",
+        ]);
+        let node = node_cli(
+            &cli,
+            NodeConfig {
+                name: "alpha".into(),
+                socket: Some("/alpha/lightning-rpc".into()),
+                args: vec!["--epoch-length".into(), "12".into()],
+            },
+        )
+        .unwrap();
+        assert_eq!(node.node_name, "alpha");
+        assert_eq!(node.socket, Some("/alpha/lightning-rpc".into()));
+        assert_eq!(node.data_dir, cli.data_dir.join("alpha"));
+        assert_eq!((node.epochs, node.epoch_length), (4, 12));
+        assert!(node.coordinate_peers);
+        assert_eq!(node.verbose, 2);
+        assert_eq!(node.exclude_peers, vec!["a", "b"]);
+
+        let node = node_cli(
+            &cli,
+            NodeConfig {
+                name: "beta".into(),
+                socket: Some("/beta/lightning-rpc".into()),
+                args: ["--epochs", "2", "-d", "/beta", "--exclude-peers", "c"]
+                    .map(String::from)
+                    .to_vec(),
+            },
+        )
+        .unwrap();
+        assert_eq!((node.epochs, node.epoch_length), (2, 24));
+        assert_eq!(node.data_dir, PathBuf::from("/beta"));
+        assert_eq!(node.exclude_peers, vec!["c"]);
+        assert!(node_cli(
+            &cli,
+            NodeConfig {
+                name: "gamma".into(),
+                socket: Some("/gamma/lightning-rpc".into()),
+                args: vec!["--epochs".into(), "many".into()],
+            },
+        )
+        .is_err());
+
+        let node = node_cli(
+            &cli,
+            NodeConfig {
+                name: "delta".into(),
+                socket: None,
+                args: [
+                    "--grpc-endpoint",
+                    "https://delta:9736",
+                    "--grpc-ca-cert",
+                    "/delta/ca.pem",
+                    "--grpc-client-cert",
+                    "/delta/client.pem",
+                    "--grpc-client-key",
+                    "/delta/client-key.pem",
+                ]
+                .map(String::from)
+                .to_vec(),
+            },
+        )
+        .unwrap();
+        assert_eq!(node.socket, None);
+        assert_eq!(node.grpc_endpoint, Some("https://delta:9736".into()));
+        assert_eq!(node.grpc_ca_cert, Some("/delta/ca.pem".into()));
+    }
+    #[test]
+    fn keep_command_of_command_line() {
+        let cli = Cli::parse_from([
+            "cln-feeder",
+            "--nodes",
+            "nodes.json",
+            "--node",
+            "alpha",
+            "--flaky-threshold",
+            "80",
+            "explain",
+            "1x2x3",
+        ]);
+        let node = node_cli(
+            &cli,
+            NodeConfig {
+                name: "alpha".into(),
+                socket: Some("/alpha/lightning-rpc".into()),
+                args: vec!["--flaky-threshold".into(), "95".into()],
+            },
+        )
+        .unwrap();
+        assert_eq!(node.flaky_threshold, 95.0);
+        assert!(matches!(node.command, Some(Command::Explain { channel }) if channel == "1x2x3"));
+        assert!(node_cli(
+            &cli,
+            NodeConfig {
+                name: "beta".into(),
+                socket: None,
+                args: vec!["uptime".into()],
+            },
+        )
+        .is_err());
+    }
+    #[test]
+    fn nodes_can_set_every_option() {
+        let options: Vec<String> = NODE_OPTIONS
+            .iter()
+            .map(|option| option.replace('_', "-"))
+            .collect();
+        for arg in Cli::command().get_arguments() {
+            assert!(
+                options.iter().any(|option| option == arg.get_id())
+                    || ["nodes", "node", "help", "version"].contains(&arg.get_id()),
+                "{} can't be set per node",
+                arg.get_id()
+            );
+        }
+    }
+}
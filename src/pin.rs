@@ -0,0 +1,97 @@
+use crate::db::{remove_pin, store_decision, store_pin, Decision, Decisions, Pin};
+use crate::metrics::FEE_CHANGES;
+use crate::report::format_time;
+use crate::rpc::{get_current_channels, get_current_peers, set_channel_fee, Client};
+use crate::Cli;
+use anyhow::{ensure, Result};
+use chrono::Utc;
+use log::info;
+use rusqlite::Connection;
+
+/// Describes a pin as the reason of a decision
+pub fn pin_reason(pin: &Pin) -> String {
+    match pin.until {
+        Some(until) => format!(
+            "Fee is pinned at {} ppm until {}",
+            pin.fee,
+            format_time(until)
+        ),
+        None => format!("Fee is pinned at {} ppm", pin.fee),
+    }
+}
+/// Pins the fee of a channel by its short channel id or channel id and sets it on the node right
+/// away. Returns `None` if the channel is not known to the node.
+pub async fn pin_fee(
+    cli: &Cli,
+    channel: &String,
+    fee: u32,
+    until: Option<i64>,
+    client: &mut Client,
+    db: &mut Connection,
+    decisions: &Decisions,
+) -> Result<Option<Pin>> {
+    let now = Utc::now().timestamp();
+    ensure!(
+        !matches!(until, Some(until) if until <= now),
+        "The pin would already be expired"
+    );
+    let peers = get_current_peers(client).await?;
+    let current_channels = get_current_channels(peers);
+    let (id, current) = if let Some(current) = current_channels
+        .iter()
+        .find(|(id, current)| *id == channel || current.channel_id == *channel)
+    {
+        current
+    } else {
+        return Ok(None);
+    };
+    let pin = Pin {
+        channel_id: current.channel_id.clone(),
+        short_channel_id: id.clone(),
+        fee,
+        until,
+        pinned: now,
+    };
+    store_pin(db, &pin);
+    info!("{}: Pinned fee {} -> {} ppm", id, current.fee, fee);
+    let success = set_channel_fee(client, id, fee).await;
+    if success && fee != current.fee {
+        FEE_CHANGES.with_label_values(&[&cli.node_name]).inc();
+    }
+    store_decision(
+        db,
+        &Decision {
+            channel_id: pin.channel_id.clone(),
+            short_channel_id: id.clone(),
+            time: now,
+            aggregates: None,
+            reason: pin_reason(&pin),
+            proposed_fee: Some(fee),
+            applied_fee: Some(fee),
+            success: Some(success),
+        },
+        decisions,
+    );
+    Ok(Some(pin))
+}
+/// Removes the pin of a channel by its short channel id or channel id so that the feeder manages
+/// it again. Returns `None` if the channel wasn't pinned.
+pub fn unpin_fee(channel: &String, db: &mut Connection, decisions: &Decisions) -> Option<Pin> {
+    let pin = remove_pin(db, channel)?;
+    info!("{}: Unpinned fee", pin.short_channel_id);
+    store_decision(
+        db,
+        &Decision {
+            channel_id: pin.channel_id.clone(),
+            short_channel_id: pin.short_channel_id.clone(),
+            time: Utc::now().timestamp(),
+            aggregates: None,
+            reason: String::from("Fee was unpinned"),
+            proposed_fee: None,
+            applied_fee: None,
+            success: None,
+        },
+        decisions,
+    );
+    Some(pin)
+}
@@ -0,0 +1,232 @@
+use crate::db::{query_decisions, query_epoch_count, query_last_channel_values, query_pin};
+use crate::filter::exclusion;
+use crate::gossip::get_competitor_fees;
+use crate::rpc::{
+    get_current_channels, get_current_peers, get_node_aliases, get_node_id, Client, Management,
+};
+use crate::{Cli, Epoch};
+use anyhow::Result;
+use chrono::{TimeZone, Utc};
+use rusqlite::Connection;
+use serde::Serialize;
+
+fn format_fee(fee: Option<u32>) -> String {
+    fee.map(|fee| fee.to_string()).unwrap_or_else(|| "-".into())
+}
+/// Shortens an alias to the first 20 characters, which may take several bytes each
+pub fn format_alias(alias: &str) -> String {
+    alias.chars().take(20).collect()
+}
+pub fn format_time(timestamp: i64) -> String {
+    Utc.timestamp_opt(timestamp, 0)
+        .unwrap()
+        .format("%Y-%m-%d %H:%M")
+        .to_string()
+}
+pub fn print_decisions(channel: Option<&String>, count: u32, db: &mut Connection) {
+    println!(
+        "{:<16} {:<16} {:>8} {:>8} {:>7}  REASON",
+        "TIME", "CHANNEL", "PROPOSED", "APPLIED", "SUCCESS"
+    );
+    for decision in query_decisions(channel, None, count, db) {
+        println!(
+            "{:<16} {:<16} {:>8} {:>8} {:>7}  {}",
+            format_time(decision.time),
+            decision.short_channel_id,
+            format_fee(decision.proposed_fee),
+            format_fee(decision.applied_fee),
+            decision
+                .success
+                .map(|success| success.to_string())
+                .unwrap_or_else(|| "-".into()),
+            decision.reason
+        );
+    }
+}
+/// Formats the time until a timestamp as hours and minutes
+fn format_until(timestamp: i64, now: i64) -> String {
+    if timestamp <= now {
+        return "due".into();
+    }
+    let minutes = (timestamp - now) / 60;
+    format!("{}h {:02}m", minutes / 60, minutes % 60)
+}
+/// What the feeder currently knows about a channel of the node
+#[derive(Serialize)]
+pub struct ChannelStatus {
+    pub short_channel_id: String,
+    pub channel_id: String,
+    pub peer_id: String,
+    pub alias: Option<String>,
+    pub connected: bool,
+    pub state: String,
+    pub management: String,
+    pub fee: u32,
+    pub last_revenue: Option<u64>,
+    pub epochs: u32,
+    pub next_adjustment: Option<i64>,
+    pub last_decision: Option<String>,
+    pub pinned_fee: Option<u32>,
+    pub pinned_until: Option<i64>,
+    /// The lowest fee that the competitor floor allows
+    pub competitor_floor: Option<u32>,
+    /// The highest fee that the competitor ceiling allows
+    pub competitor_ceiling: Option<u32>,
+    /// Why the channel is excluded from management
+    pub excluded: Option<String>,
+}
+pub async fn query_channel_statuses(
+    cli: &Cli,
+    client: &mut Client,
+    db: &mut Connection,
+) -> Result<Vec<ChannelStatus>> {
+    let peers = get_current_peers(client).await?;
+    let aliases = get_node_aliases(client).await?;
+    let now = Utc::now().timestamp();
+    let mut current_channels: Vec<_> = get_current_channels(peers).into_iter().collect();
+    current_channels.sort_by(|(a, _), (b, _)| a.cmp(b));
+    // Our node id is only needed to tell our channels apart from competitors in the graph
+    let node_id = if cli.competitor_floor.is_some() || cli.competitor_ceiling.is_some() {
+        Some(get_node_id(client).await?)
+    } else {
+        None
+    };
+
+    let mut statuses = vec![];
+    for (id, channel) in current_channels {
+        let last_values = query_last_channel_values(&channel.channel_id, cli.epochs, db);
+        let next_adjustment = if channel.management == Management::Managed {
+            Some(Epoch::current(cli, &channel.channel_id, &last_values, now, db).end)
+        } else {
+            None
+        };
+        let pin = query_pin(&channel.channel_id, db);
+        let (competitor_floor, competitor_ceiling) = if let Some(node_id) = &node_id {
+            let competitors = get_competitor_fees(&channel.peer_id, node_id, client).await?;
+            (
+                cli.competitor_floor
+                    .and_then(|floor| competitors.percentile(floor)),
+                cli.competitor_ceiling
+                    .and_then(|ceiling| competitors.percentile(ceiling)),
+            )
+        } else {
+            (None, None)
+        };
+        statuses.push(ChannelStatus {
+            excluded: exclusion(cli, &id, &channel),
+            pinned_fee: pin.as_ref().map(|pin| pin.fee),
+            pinned_until: pin.and_then(|pin| pin.until),
+            competitor_floor,
+            competitor_ceiling,
+            alias: aliases.get(&channel.peer_id).cloned(),
+            connected: channel.connected,
+            state: channel.state,
+            management: format!("{:?}", channel.management),
+            fee: channel.fee,
+            last_revenue: last_values.first().map(|(_, _, revenue)| *revenue),
+            epochs: query_epoch_count(&channel.channel_id, db),
+            next_adjustment,
+            last_decision: query_decisions(Some(&channel.channel_id), None, 1, db)
+                .pop()
+                .map(|decision| decision.reason),
+            short_channel_id: id,
+            channel_id: channel.channel_id,
+            peer_id: channel.peer_id,
+        });
+    }
+    Ok(statuses)
+}
+/// Prints what the feeder currently knows about each channel of the node
+pub async fn print_status(
+    cli: &Cli,
+    json: bool,
+    client: &mut Client,
+    db: &mut Connection,
+) -> Result<()> {
+    let statuses = query_channel_statuses(cli, client, db).await?;
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&statuses).expect("Couldn't serialize status")
+        );
+        return Ok(());
+    }
+    print_statuses(statuses);
+    Ok(())
+}
+/// Prints the statuses as a table
+pub fn print_statuses(statuses: Vec<ChannelStatus>) {
+    let now = Utc::now().timestamp();
+    println!(
+        "{:<16} {:<20} {:>8} {:>8} {:>8} {:>8} {:>14} {:>6} {:>9} {:<10}  LAST DECISION",
+        "CHANNEL",
+        "ALIAS",
+        "PPM",
+        "PINNED",
+        "FLOOR",
+        "CEILING",
+        "LAST REVENUE",
+        "EPOCHS",
+        "NEXT",
+        "STATE"
+    );
+    for status in statuses {
+        let alias = format_alias(status.alias.as_ref().unwrap_or(&status.peer_id));
+        println!(
+            "{:<16} {:<20} {:>8} {:>8} {:>8} {:>8} {:>14} {:>6} {:>9} {:<10}  {}",
+            status.short_channel_id,
+            alias,
+            status.fee,
+            format_fee(status.pinned_fee),
+            format_fee(status.competitor_floor),
+            format_fee(status.competitor_ceiling),
+            status
+                .last_revenue
+                .map(|revenue| revenue.to_string())
+                .unwrap_or_else(|| "-".into()),
+            status.epochs,
+            status
+                .next_adjustment
+                .map(|end| format_until(end, now))
+                .unwrap_or_else(|| "-".into()),
+            match (status.excluded.is_some(), status.connected) {
+                (false, true) => status.management,
+                (false, false) => format!("{} offline", status.management),
+                (true, true) => String::from("Excluded"),
+                (true, false) => String::from("Excluded offline"),
+            },
+            status.last_decision.unwrap_or_else(|| "-".into())
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn print_multibyte_alias() {
+        // The 20th byte is inside the second lightning bolt
+        let alias = "Lightning Node ⚡⚡⚡⚡ and more";
+        assert_eq!(format_alias(alias), "Lightning Node ⚡⚡⚡⚡ ");
+        assert_eq!(format_alias("short"), "short");
+        print_statuses(vec![ChannelStatus {
+            short_channel_id: "1x2x3".into(),
+            channel_id: "ab".repeat(32),
+            peer_id: "03".repeat(33),
+            alias: Some(alias.into()),
+            connected: true,
+            state: "CHANNELD_NORMAL".into(),
+            management: "Managed".into(),
+            fee: 100,
+            last_revenue: None,
+            epochs: 0,
+            next_adjustment: None,
+            last_decision: None,
+            pinned_fee: None,
+            pinned_until: None,
+            competitor_floor: None,
+            competitor_ceiling: None,
+            excluded: None,
+        }]);
+    }
+}
@@ -0,0 +1,339 @@
+use crate::db::{query_open_channels, store_closed_channel};
+use crate::metrics::{
+    CHANNEL_BALANCE_RATIO, CHANNEL_EPOCH_FORWARDS, CHANNEL_EPOCH_REVENUE, CHANNEL_FEE,
+};
+use crate::rpc::Peer;
+use clap::ValueEnum;
+use cln_rpc::model::ListpeersPeersChannelsState;
+use log::{debug, info};
+use rusqlite::{Connection, Transaction};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// The tables that store rows of a channel under its channel id. The closed channels come last
+/// because they select the rows of the others.
+const CHANNEL_TABLES: &[&str] = &[
+    "channels",
+    "decisions",
+    "offline_intervals",
+    "external_changes",
+    "pins",
+    "closed_channels",
+];
+/// The tables that store rows of a peer under its node id
+const PEER_TABLES: &[&str] = &["peers", "peer_uptime"];
+const CLOSED_BEFORE: &str = "SELECT channel_id FROM main.closed_channels WHERE closed < ?1";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum RetentionAction {
+    /// Move the history into `archive.sqlite` in the data directory
+    Archive,
+    /// Delete the history
+    Delete,
+}
+
+/// Marks the channels with history as closed that the node no longer lists or that are onchain,
+/// and removes their metrics. Channels that are listed without a fee or channel id aren't
+/// closed. Returns the short channel ids of the newly closed channels.
+pub fn mark_closed_channels(
+    node: &str,
+    peers: &[Peer],
+    now: i64,
+    db: &mut Connection,
+) -> Vec<String> {
+    // History that isn't linked yet is still stored under the short channel id
+    let open: HashSet<&String> = peers
+        .iter()
+        .flat_map(|peer| &peer.channels)
+        .filter(|channel| !matches!(channel.state, ListpeersPeersChannelsState::ONCHAIN))
+        .flat_map(|channel| [&channel.short_channel_id, &channel.channel_id])
+        .flatten()
+        .collect();
+    let mut closed = vec![];
+    for (channel_id, id) in query_open_channels(db) {
+        if open.contains(&channel_id) || open.contains(&id) {
+            continue;
+        }
+        info!("{}: Channel closed", id);
+        store_closed_channel(db, &channel_id, &id, now);
+        let _ = CHANNEL_FEE.remove_label_values(&[node, &id]);
+        let _ = CHANNEL_BALANCE_RATIO.remove_label_values(&[node, &id]);
+        let _ = CHANNEL_EPOCH_REVENUE.remove_label_values(&[node, &id]);
+        let _ = CHANNEL_EPOCH_FORWARDS.remove_label_values(&[node, &id]);
+        closed.push(id);
+    }
+    closed
+}
+
+/// Returns the node ids of the peers that have a channel that isn't onchain
+pub fn peers_with_open_channels(peers: &[Peer]) -> HashSet<String> {
+    peers
+        .iter()
+        .filter(|peer| {
+            peer.channels
+                .iter()
+                .any(|channel| !matches!(channel.state, ListpeersPeersChannelsState::ONCHAIN))
+        })
+        .map(|peer| peer.id.clone())
+        .collect()
+}
+
+fn columns_of(tx: &Transaction, schema: &str, table: &str) -> Vec<String> {
+    tx.prepare("SELECT name FROM pragma_table_info(?1, ?2)")
+        .expect("Couldn't prepare query for columns")
+        .query_map([table, schema], |row| row.get(0))
+        .expect("Couldn't query columns")
+        .map(|row| row.unwrap())
+        .collect()
+}
+/// Creates the table in the archive or adds the columns that migrations added to the table
+/// since it was archived first. Returns the columns of the table to copy.
+fn archive_table(tx: &Transaction, table: &str) -> String {
+    tx.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS archive.{0} AS SELECT * FROM main.{0} WHERE 0",
+        table
+    ))
+    .expect("Couldn't create archive table");
+    let archived = columns_of(tx, "archive", table);
+    let columns = columns_of(tx, "main", table);
+    for column in columns.iter().filter(|column| !archived.contains(column)) {
+        tx.execute_batch(&format!(
+            "ALTER TABLE archive.{} ADD COLUMN {}",
+            table, column
+        ))
+        .expect("Couldn't add column to archive table");
+    }
+    columns.join(", ")
+}
+
+/// Removes all rows of the channels that closed before `before` and of the peers that aren't in
+/// `open_peers` anymore, and vacuums the database. The rows are copied into the database at
+/// `archive` first if one is given. Returns the number of pruned channels.
+pub fn prune_closed_channels(
+    before: i64,
+    open_peers: &HashSet<String>,
+    archive: Option<&Path>,
+    db: &mut Connection,
+) -> u32 {
+    let count: u32 = db
+        .query_row(
+            &format!("SELECT COUNT(*) FROM ({})", CLOSED_BEFORE),
+            [before],
+            |row| row.get(0),
+        )
+        .expect("Couldn't count closed channels");
+    if count == 0 {
+        return 0;
+    }
+    if let Some(archive) = archive {
+        if let Some(dir) = archive.parent() {
+            std::fs::create_dir_all(dir).expect("Couldn't create archive dir");
+        }
+        db.execute("ATTACH DATABASE ?1 AS archive", [archive.to_string_lossy()])
+            .expect("Couldn't attach archive");
+    }
+    let tx = db.transaction().expect("Couldn't start pruning");
+    for table in CHANNEL_TABLES {
+        if archive.is_some() {
+            let columns = archive_table(&tx, table);
+            tx.execute(
+                &format!(
+                    "INSERT INTO archive.{0} ({1}) SELECT {1} FROM main.{0} \
+                        WHERE channel_id IN ({2})",
+                    table, columns, CLOSED_BEFORE
+                ),
+                [before],
+            )
+            .expect("Couldn't archive closed channels");
+        }
+        let deleted = tx
+            .execute(
+                &format!(
+                    "DELETE FROM main.{} WHERE channel_id IN ({})",
+                    table, CLOSED_BEFORE
+                ),
+                [before],
+            )
+            .expect("Couldn't prune closed channels");
+        debug!("Pruned {} rows from {}", deleted, table);
+    }
+    for table in PEER_TABLES {
+        let departed: Vec<String> = tx
+            .prepare(&format!("SELECT DISTINCT peer_id FROM main.{}", table))
+            .expect("Couldn't prepare query for peers")
+            .query_map([], |row| row.get(0))
+            .expect("Couldn't query peers")
+            .map(|row| row.unwrap())
+            .filter(|peer_id| !open_peers.contains(peer_id))
+            .collect();
+        let columns = archive.map(|_| archive_table(&tx, table));
+        let mut deleted = 0;
+        for peer_id in &departed {
+            if let Some(columns) = &columns {
+                tx.execute(
+                    &format!(
+                        "INSERT INTO archive.{0} ({1}) SELECT {1} FROM main.{0} \
+                            WHERE peer_id IS ?1",
+                        table, columns
+                    ),
+                    [peer_id],
+                )
+                .expect("Couldn't archive peers");
+            }
+            deleted += tx
+                .execute(
+                    &format!("DELETE FROM main.{} WHERE peer_id IS ?1", table),
+                    [peer_id],
+                )
+                .expect("Couldn't prune peers");
+        }
+        debug!("Pruned {} rows from {}", deleted, table);
+    }
+    tx.commit().expect("Couldn't commit pruning");
+    if archive.is_some() {
+        db.execute("DETACH DATABASE archive", [])
+            .expect("Couldn't detach archive");
+    }
+    db.execute("VACUUM", []).expect("Couldn't vacuum database");
+    info!("Pruned the history of {} closed channels", count);
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{
+        store_decision, store_peer_observation, store_peer_values, store_values, Decision,
+    };
+    use crate::migrations::migrate;
+    use crate::rpc::PeerChannel;
+    use tokio::sync::broadcast;
+    use ListpeersPeersChannelsState::*;
+    #[test]
+    fn mark_and_prune_closed_channels() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db);
+        let (open_id, closed_id) = ("aa".repeat(32), "bb".repeat(32));
+        store_values(&mut db, &open_id, "1x1x0".into(), 100, 0, 1000);
+        store_values(&mut db, &closed_id, "2x2x0".into(), 200, 0, 1000);
+        store_values(&mut db, &closed_id, "2x2x0".into(), 300, 0, 2000);
+        let (unpriced_id, onchain_id) = ("cc".repeat(32), "dd".repeat(32));
+        store_values(&mut db, &unpriced_id, "3x3x0".into(), 100, 0, 1000);
+        store_values(&mut db, &onchain_id, "4x4x0".into(), 100, 0, 1000);
+        let channel = |id: &str, channel_id: &String, fee, state| PeerChannel {
+            state,
+            short_channel_id: Some(id.into()),
+            channel_id: Some(channel_id.clone()),
+            fee,
+            private: false,
+            to_us_msat: 0,
+            total_msat: 0,
+        };
+        let current = vec![Peer {
+            id: "03".repeat(33),
+            connected: true,
+            channels: vec![
+                channel("1x1x0", &open_id, Some(100), CHANNELD_NORMAL),
+                // Skipped by get_current_channels but still open
+                channel("3x3x0", &unpriced_id, None, CHANNELD_NORMAL),
+                channel("4x4x0", &onchain_id, Some(100), ONCHAIN),
+            ],
+        }];
+        assert_eq!(
+            mark_closed_channels("", &current, 3000, &mut db),
+            vec!["2x2x0", "4x4x0"]
+        );
+        assert!(mark_closed_channels("", &current, 4000, &mut db).is_empty());
+        assert_eq!(
+            query_open_channels(&mut db),
+            vec![(open_id, "1x1x0".into()), (unpriced_id, "3x3x0".into())]
+        );
+
+        let archive =
+            std::env::temp_dir().join(format!("cln-feeder-archive-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&archive);
+        let departed_id = "02".repeat(33);
+        for peer_id in [&current[0].id, &departed_id] {
+            store_peer_values(&mut db, peer_id, 100, 1000);
+            store_peer_observation(&mut db, peer_id, true);
+        }
+        let open_peers = peers_with_open_channels(&current);
+        assert_eq!(
+            prune_closed_channels(3000, &open_peers, Some(&archive), &mut db),
+            0
+        );
+        assert_eq!(
+            prune_closed_channels(3001, &open_peers, Some(&archive), &mut db),
+            2
+        );
+        let rows = |db: &Connection, table: &str| -> u32 {
+            db.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(rows(&db, "channels"), 2);
+        assert_eq!(rows(&db, "closed_channels"), 0);
+        let archived = Connection::open(&archive).unwrap();
+        assert_eq!(rows(&archived, "channels"), 3);
+        assert_eq!(rows(&archived, "closed_channels"), 2);
+        assert_eq!(rows(&db, "peers"), 1);
+        assert_eq!(rows(&db, "peer_uptime"), 1);
+        assert_eq!(rows(&archived, "peers"), 1);
+        assert_eq!(rows(&archived, "peer_uptime"), 1);
+        std::fs::remove_file(&archive).unwrap();
+    }
+    #[test]
+    fn archive_into_older_schema() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db);
+        let channel_id = "bb".repeat(32);
+        store_values(&mut db, &channel_id, "2x2x0".into(), 200, 0, 1000);
+        let decision = Decision {
+            channel_id: channel_id.clone(),
+            short_channel_id: "2x2x0".into(),
+            time: 1000,
+            aggregates: None,
+            reason: String::from("Halving fee to search for revenue"),
+            proposed_fee: Some(100),
+            applied_fee: Some(100),
+            success: Some(true),
+        };
+        store_decision(&mut db, &decision, &broadcast::channel(1).0);
+        store_closed_channel(&mut db, &channel_id, &"2x2x0".into(), 2000);
+
+        // Archived before decisions had an id
+        let archive = std::env::temp_dir().join(format!(
+            "cln-feeder-old-archive-{}.sqlite",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&archive);
+        Connection::open(&archive)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE decisions (channel_id, short_channel_id, time, reason); \
+                INSERT INTO decisions VALUES ('aa', '1x1x0', 500, 'Increasing fee');",
+            )
+            .unwrap();
+        assert_eq!(
+            prune_closed_channels(3000, &HashSet::new(), Some(&archive), &mut db),
+            1
+        );
+        let archived = Connection::open(&archive).unwrap();
+        let reasons: Vec<(Option<i64>, String, Option<u32>)> = archived
+            .prepare("SELECT id, reason, applied_fee FROM decisions ORDER BY time")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (None, String::from("Increasing fee"), None),
+                (Some(1), decision.reason, Some(100)),
+            ]
+        );
+        std::fs::remove_file(&archive).unwrap();
+    }
+}
@@ -1,73 +1,475 @@
+use crate::cln_grpc;
+use crate::cln_grpc::proto::node_client::NodeClient;
+use crate::gossip::GossipChannel;
+use crate::metrics::RPC_ERRORS;
+use anyhow::{anyhow, Context, Result};
 use cln_rpc::model::*;
-use cln_rpc::primitives::ShortChannelId;
+use cln_rpc::primitives::{PublicKey, ShortChannelId};
 use cln_rpc::ClnRpc;
-use log::debug;
+use log::{debug, error, warn};
 use std::collections::HashMap;
+use std::str::FromStr;
+use tonic::transport::Channel;
 
-pub async fn get_revenue_since(
+/// A connection to CLN over its Unix socket or over cln-grpc
+enum Backend {
+    Socket(ClnRpc),
+    Grpc(NodeClient<Channel>),
+}
+/// A connection to a node. Failed calls are counted for the node.
+pub struct Client {
+    node: String,
+    backend: Backend,
+}
+impl Client {
+    pub fn socket(node: &str, rpc: ClnRpc) -> Self {
+        Self {
+            node: node.to_string(),
+            backend: Backend::Socket(rpc),
+        }
+    }
+    pub fn grpc(node: &str, grpc: NodeClient<Channel>) -> Self {
+        Self {
+            node: node.to_string(),
+            backend: Backend::Grpc(grpc),
+        }
+    }
+}
+/// Calls CLN over the socket and counts failed calls
+async fn call(node: &str, rpc: &mut ClnRpc, request: Request) -> Result<Response> {
+    rpc.call(request).await.map_err(|e| {
+        RPC_ERRORS.with_label_values(&[node]).inc();
+        anyhow!("{:?}", e)
+    })
+}
+/// Counts failed calls over gRPC
+fn counted<T>(node: &str, result: Result<T>) -> Result<T> {
+    if result.is_err() {
+        RPC_ERRORS.with_label_values(&[node]).inc();
+    }
+    result
+}
+/// The settled forwards out of a channel
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Forwards {
+    pub revenue: u64,
+    pub count: u64,
+}
+pub async fn get_forwards_since(
     last_updated: i64,
     short_channel_id: ShortChannelId,
-    client: &mut ClnRpc,
-) -> u64 {
-    let mut revenue = 0;
-    if let Response::ListForwards(forwards) = client
-        .call(Request::ListForwards(ListforwardsRequest {
+    client: &mut Client,
+) -> Result<Forwards> {
+    let mut forwards = Forwards::default();
+    for (received_time, fee) in get_forward_fees(short_channel_id, client)
+        .await
+        .context("Couldn't get current revenue")?
+    {
+        if received_time > last_updated as f64 {
+            forwards.revenue = forwards.revenue.saturating_add(fee);
+            forwards.count += 1;
+        }
+    }
+    Ok(forwards)
+}
+/// Returns when each settled forward out of a channel was received and the fee it earned
+pub async fn get_forward_fees(
+    short_channel_id: ShortChannelId,
+    client: &mut Client,
+) -> Result<Vec<(f64, u64)>> {
+    let rpc = match &mut client.backend {
+        Backend::Socket(rpc) => rpc,
+        Backend::Grpc(grpc) => {
+            return counted(
+                &client.node,
+                cln_grpc::get_forward_fees(short_channel_id.to_string(), grpc).await,
+            )
+            .context("Couldn't get forwards");
+        }
+    };
+    if let Response::ListForwards(response) = call(
+        &client.node,
+        rpc,
+        Request::ListForwards(ListforwardsRequest {
             status: Some(ListforwardsStatus::SETTLED),
             in_channel: None,
             out_channel: Some(short_channel_id),
-        }))
-        .await
-        .expect("Couldn't get current revenue")
+        }),
+    )
+    .await
+    .context("Couldn't get forwards")?
     {
-        for payment in forwards.forwards {
-            if payment.received_time > last_updated as f64 {
-                revenue += payment.fee_msat.unwrap().msat();
-            }
+        Ok(response
+            .forwards
+            .into_iter()
+            .map(|payment| {
+                (
+                    payment.received_time,
+                    payment.fee_msat.map(|fee| fee.msat()).unwrap_or(0),
+                )
+            })
+            .collect())
+    } else {
+        Ok(vec![])
+    }
+}
+/// A peer of the node and the channels with it
+pub struct Peer {
+    pub id: String,
+    pub connected: bool,
+    pub channels: Vec<PeerChannel>,
+}
+pub struct PeerChannel {
+    pub state: ListpeersPeersChannelsState,
+    pub short_channel_id: Option<String>,
+    pub channel_id: Option<String>,
+    pub fee: Option<u32>,
+    pub private: bool,
+    pub to_us_msat: u64,
+    pub total_msat: u64,
+}
+impl From<ListpeersPeers> for Peer {
+    fn from(peer: ListpeersPeers) -> Self {
+        Peer {
+            id: peer.id.to_string(),
+            connected: peer.connected,
+            channels: peer
+                .channels
+                .into_iter()
+                .map(|channel| PeerChannel {
+                    state: channel.state,
+                    short_channel_id: channel.short_channel_id.map(|id| id.to_string()),
+                    channel_id: channel.channel_id.map(|id| id.to_string()),
+                    fee: channel.fee_proportional_millionths,
+                    private: channel.private.unwrap_or(false),
+                    to_us_msat: channel.to_us_msat.map(|a| a.msat()).unwrap_or(0),
+                    total_msat: channel.total_msat.map(|a| a.msat()).unwrap_or(0),
+                })
+                .collect(),
         }
     }
-    revenue
 }
-pub async fn get_current_peers(client: &mut ClnRpc) -> Vec<ListpeersPeers> {
-    if let Response::ListPeers(peers) = client
-        .call(Request::ListPeers(ListpeersRequest {
+pub async fn get_current_peers(client: &mut Client) -> Result<Vec<Peer>> {
+    let rpc = match &mut client.backend {
+        Backend::Socket(rpc) => rpc,
+        Backend::Grpc(grpc) => {
+            return counted(&client.node, cln_grpc::get_peers(grpc).await)
+                .context("Couldn't get peers");
+        }
+    };
+    if let Response::ListPeers(peers) = call(
+        &client.node,
+        rpc,
+        Request::ListPeers(ListpeersRequest {
             id: None,
             level: None,
-        }))
+        }),
+    )
+    .await
+    .context("Couldn't get peers")?
+    {
+        Ok(peers.peers.into_iter().map(Peer::from).collect())
+    } else {
+        Ok(vec![])
+    }
+}
+/// What the feeder needs to know about the node itself
+pub struct NodeInfo {
+    pub id: String,
+    pub network: String,
+    pub blockheight: u32,
+}
+pub async fn get_info(client: &mut Client) -> Result<NodeInfo> {
+    let rpc = match &mut client.backend {
+        Backend::Socket(rpc) => rpc,
+        Backend::Grpc(grpc) => {
+            return counted(&client.node, cln_grpc::get_info(grpc).await)
+                .context("Couldn't get node info");
+        }
+    };
+    if let Response::Getinfo(info) = call(&client.node, rpc, Request::Getinfo(GetinfoRequest {}))
         .await
-        .expect("Couldn't get peers")
+        .context("Couldn't get node info")?
     {
-        peers.peers
+        Ok(NodeInfo {
+            id: info.id.to_string(),
+            network: info.network,
+            blockheight: info.blockheight,
+        })
     } else {
-        vec![]
+        Err(anyhow!("Unexpected response to getinfo"))
     }
 }
-pub async fn get_current_fees(client: &mut ClnRpc) -> HashMap<String, u32> {
-    let mut fees = HashMap::new();
-    for peer in get_current_peers(client).await {
-        if !peer.connected {
-            continue;
+/// Returns the node id of our node
+pub async fn get_node_id(client: &mut Client) -> Result<String> {
+    Ok(get_info(client).await?.id)
+}
+/// Returns the channels in the gossip graph that lead into a node
+pub async fn get_channels_into(peer_id: &str, client: &mut Client) -> Result<Vec<GossipChannel>> {
+    get_gossip_channels(Some(peer_id), client).await
+}
+/// Returns all channels in the gossip graph
+pub async fn get_gossip_graph(client: &mut Client) -> Result<Vec<GossipChannel>> {
+    get_gossip_channels(None, client).await
+}
+async fn get_gossip_channels(
+    destination: Option<&str>,
+    client: &mut Client,
+) -> Result<Vec<GossipChannel>> {
+    let rpc = match &mut client.backend {
+        Backend::Socket(rpc) => rpc,
+        Backend::Grpc(grpc) => {
+            return counted(
+                &client.node,
+                cln_grpc::get_gossip_channels(destination, grpc).await,
+            )
+            .context("Couldn't get channels from gossip");
+        }
+    };
+    let destination = destination
+        .map(|destination| PublicKey::from_str(destination).map_err(|e| anyhow!("{:?}", e)))
+        .transpose()?;
+    if let Response::ListChannels(response) = call(
+        &client.node,
+        rpc,
+        Request::ListChannels(ListchannelsRequest {
+            short_channel_id: None,
+            source: None,
+            destination,
+        }),
+    )
+    .await
+    .context("Couldn't get channels from gossip")?
+    {
+        Ok(response
+            .channels
+            .into_iter()
+            .map(|channel| GossipChannel {
+                short_channel_id: channel.short_channel_id.to_string(),
+                source: channel.source.to_string(),
+                destination: channel.destination.to_string(),
+                fee_per_millionth: channel.fee_per_millionth,
+                active: channel.active,
+            })
+            .collect())
+    } else {
+        Ok(vec![])
+    }
+}
+/// How the feeder treats a channel depending on its state
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Management {
+    /// The channel is routing and its fees are adjusted
+    Managed,
+    /// The channel isn't routing yet. Its fee is left alone until it is
+    Frozen,
+    /// The channel is closing or closed and won't route again
+    Closing,
+}
+impl Management {
+    /// This cln-rpc has no splicing states. A splice in progress keeps the channel in
+    /// `CHANNELD_NORMAL`, so it stays managed while splicing.
+    pub fn of(state: &ListpeersPeersChannelsState) -> Self {
+        use ListpeersPeersChannelsState::*;
+        match state {
+            CHANNELD_NORMAL => Management::Managed,
+            OPENINGD
+            | DUALOPEND_OPEN_INIT
+            | CHANNELD_AWAITING_LOCKIN
+            | DUALOPEND_AWAITING_LOCKIN => Management::Frozen,
+            CHANNELD_SHUTTING_DOWN
+            | CLOSINGD_SIGEXCHANGE
+            | CLOSINGD_COMPLETE
+            | AWAITING_UNILATERAL
+            | FUNDING_SPEND_SEEN
+            | ONCHAIN => Management::Closing,
+        }
+    }
+}
+/// Returns the aliases of all nodes in the graph that announced one
+pub async fn get_node_aliases(client: &mut Client) -> Result<HashMap<String, String>> {
+    let rpc = match &mut client.backend {
+        Backend::Socket(rpc) => rpc,
+        Backend::Grpc(grpc) => {
+            return counted(&client.node, cln_grpc::get_node_aliases(grpc).await)
+                .context("Couldn't get nodes");
         }
+    };
+    if let Response::ListNodes(nodes) = call(
+        &client.node,
+        rpc,
+        Request::ListNodes(ListnodesRequest { id: None }),
+    )
+    .await
+    .context("Couldn't get nodes")?
+    {
+        Ok(nodes
+            .nodes
+            .into_iter()
+            .filter_map(|node| Some((node.nodeid.to_string(), node.alias?)))
+            .collect())
+    } else {
+        Ok(HashMap::new())
+    }
+}
+pub struct CurrentChannel {
+    pub channel_id: String,
+    pub fee: u32,
+    pub peer_id: String,
+    pub connected: bool,
+    pub state: String,
+    pub management: Management,
+    pub private: bool,
+    pub to_us_msat: u64,
+    pub total_msat: u64,
+}
+impl CurrentChannel {
+    /// The share of the channel capacity that is on our side
+    pub fn balance_ratio(&self) -> f64 {
+        if self.total_msat == 0 {
+            return 0.0;
+        }
+        self.to_us_msat as f64 / self.total_msat as f64
+    }
+}
+pub fn get_current_channels(peers: Vec<Peer>) -> HashMap<String, CurrentChannel> {
+    let mut channels = HashMap::new();
+    for peer in peers {
+        let peer_id = peer.id;
         for channel in peer.channels {
-            if let ListpeersPeersChannelsState::CHANNELD_NORMAL = channel.state {
-                fees.insert(
-                    channel.short_channel_id.unwrap().to_string(),
-                    channel.fee_proportional_millionths.unwrap(),
+            let state = format!("{:?}", channel.state);
+            let management = Management::of(&channel.state);
+            let id = if let Some(short_channel_id) = channel.short_channel_id {
+                short_channel_id
+            } else {
+                debug!(
+                    "{}: Skipped channel in state {} without short channel id",
+                    peer_id, state
                 );
-            }
+                continue;
+            };
+            let channel_id = if let Some(channel_id) = channel.channel_id {
+                channel_id
+            } else {
+                warn!(
+                    "{}: Skipped channel in state {} without channel id",
+                    id, state
+                );
+                continue;
+            };
+            let fee = if let Some(fee) = channel.fee {
+                fee
+            } else {
+                warn!("{}: Skipped channel in state {} without fee", id, state);
+                continue;
+            };
+            channels.insert(
+                id,
+                CurrentChannel {
+                    channel_id,
+                    fee,
+                    peer_id: peer_id.clone(),
+                    connected: peer.connected,
+                    state,
+                    management,
+                    private: channel.private,
+                    to_us_msat: channel.to_us_msat,
+                    total_msat: channel.total_msat,
+                },
+            );
         }
     }
-    fees
+    channels
 }
-pub async fn set_channel_fee(client: &mut ClnRpc, channel: &String, fee: u32) {
-    client
-        .call(Request::SetChannel(SetChannelRequest {
-            id: channel.clone(),
-            feebase: None,
-            feeppm: Some(fee),
-            htlcmin_masat: None,
-            htlcmax_msat: None,
-        }))
+/// Returns whether the fee was set
+pub async fn set_channel_fee(client: &mut Client, channel: &String, fee: u32) -> bool {
+    let result = match &mut client.backend {
+        Backend::Socket(rpc) => call(
+            &client.node,
+            rpc,
+            Request::SetChannel(SetChannelRequest {
+                id: channel.clone(),
+                feebase: None,
+                feeppm: Some(fee),
+                htlcmin_masat: None,
+                htlcmax_msat: None,
+            }),
+        )
         .await
-        .expect("Couldn't set new fee");
-    debug!("{}: Set fee {} msats", channel, fee);
+        .map(|_| ()),
+        Backend::Grpc(grpc) => counted(
+            &client.node,
+            cln_grpc::set_channel_fee(channel, fee, grpc).await,
+        ),
+    };
+    match result {
+        Ok(_) => {
+            debug!("{}: Set fee {} ppm", channel, fee);
+            true
+        }
+        Err(e) => {
+            error!("{}: Couldn't set fee {} ppm: {}", channel, fee, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn manage_channels_by_state() {
+        use ListpeersPeersChannelsState::*;
+        let states = [
+            (OPENINGD, Management::Frozen),
+            (CHANNELD_AWAITING_LOCKIN, Management::Frozen),
+            (CHANNELD_NORMAL, Management::Managed),
+            (CHANNELD_SHUTTING_DOWN, Management::Closing),
+            (CLOSINGD_SIGEXCHANGE, Management::Closing),
+            (CLOSINGD_COMPLETE, Management::Closing),
+            (AWAITING_UNILATERAL, Management::Closing),
+            (FUNDING_SPEND_SEEN, Management::Closing),
+            (ONCHAIN, Management::Closing),
+            (DUALOPEND_OPEN_INIT, Management::Frozen),
+            (DUALOPEND_AWAITING_LOCKIN, Management::Frozen),
+        ];
+        for (state, management) in &states {
+            assert_eq!(Management::of(state), *management, "{:?}", state);
+        }
+    }
+    fn channel(
+        short_channel_id: Option<&str>,
+        channel_id: Option<&str>,
+        fee: Option<u32>,
+    ) -> PeerChannel {
+        PeerChannel {
+            state: ListpeersPeersChannelsState::CHANNELD_NORMAL,
+            short_channel_id: short_channel_id.map(String::from),
+            channel_id: channel_id.map(String::from),
+            fee,
+            private: false,
+            to_us_msat: 1000,
+            total_msat: 2000,
+        }
+    }
+    #[test]
+    fn skip_incomplete_channels() {
+        let peers = vec![Peer {
+            id: "03".to_string() + &"11".repeat(32),
+            connected: true,
+            channels: vec![
+                channel(Some("1x1x1"), Some("aa"), Some(100)),
+                channel(None, Some("bb"), Some(100)),
+                channel(Some("3x3x3"), None, Some(100)),
+                channel(Some("4x4x4"), Some("dd"), None),
+            ],
+        }];
+        let channels = get_current_channels(peers);
+        assert_eq!(channels.len(), 1);
+        let current = &channels["1x1x1"];
+        assert_eq!(current.channel_id, "aa");
+        assert_eq!(current.fee, 100);
+        assert_eq!(current.management, Management::Managed);
+        assert_eq!(current.balance_ratio(), 0.5);
+    }
 }
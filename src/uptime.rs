@@ -0,0 +1,104 @@
+use crate::db::{query_observed_peers, query_peer_uptime};
+use chrono::{Duration, Utc};
+use log::debug;
+use rusqlite::Connection;
+
+/// Returns the uptime of the peer in percent over the last `hours`.
+pub fn uptime_over(peer_id: &String, hours: u32, db: &mut Connection) -> Option<f64> {
+    let since = (Utc::now() - Duration::hours(hours.into())).timestamp();
+    query_peer_uptime(peer_id, since, db)
+}
+/// A peer is flaky if it was observed over the last `hours` and was connected for less than
+/// `threshold` percent of that time.
+pub fn peer_is_flaky(peer_id: &String, hours: u32, threshold: f64, db: &mut Connection) -> bool {
+    match uptime_over(peer_id, hours, db) {
+        Some(uptime) if uptime < threshold => {
+            debug!(
+                "{}: Peer is flaky [uptime: {:.1}% over {}h]",
+                peer_id, uptime, hours
+            );
+            true
+        }
+        _ => false,
+    }
+}
+pub fn print_uptime_report(windows: &[u32], threshold: f64, db: &mut Connection) {
+    let mut header = format!("{:<66}", "PEER");
+    for hours in windows {
+        header += &format!(" {:>8}", format!("{}h", hours));
+    }
+    println!("{}", header);
+
+    let peers = query_observed_peers(db);
+    let mut flaky = 0;
+    for peer_id in &peers {
+        let mut row = format!("{:<66}", peer_id);
+        let mut is_flaky = false;
+        for hours in windows {
+            match uptime_over(peer_id, *hours, db) {
+                Some(uptime) => {
+                    is_flaky |= uptime < threshold;
+                    row += &format!(" {:>7.1}%", uptime);
+                }
+                None => row += &format!(" {:>8}", "-"),
+            }
+        }
+        if is_flaky {
+            flaky += 1;
+            row += "  unreliable";
+        }
+        println!("{}", row);
+    }
+    println!(
+        "{} of {} peers had an uptime below {}%",
+        flaky,
+        peers.len(),
+        threshold
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{remove_peer_observations_before, store_peer_observation};
+    use crate::migrations::migrate;
+    fn observe(peer_id: &String, hours_ago: i64, connected: bool, db: &mut Connection) {
+        db.execute(
+            "INSERT INTO peer_uptime (peer_id, observed, connected) VALUES (?1, ?2, ?3)",
+            (
+                peer_id,
+                (Utc::now() - Duration::hours(hours_ago)).timestamp(),
+                connected,
+            ),
+        )
+        .unwrap();
+    }
+    #[test]
+    fn flaky_below_threshold() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db);
+        let peer_id = "02".to_string() + &"aa".repeat(32);
+        assert!(!peer_is_flaky(&peer_id, 24, 90.0, &mut db));
+        // Connected in 9 of 10 observations is exactly at the threshold
+        for hours_ago in 1..10 {
+            observe(&peer_id, hours_ago, true, &mut db);
+        }
+        observe(&peer_id, 10, false, &mut db);
+        assert_eq!(uptime_over(&peer_id, 24, &mut db), Some(90.0));
+        assert!(!peer_is_flaky(&peer_id, 24, 90.0, &mut db));
+        store_peer_observation(&mut db, &peer_id, false);
+        assert!(peer_is_flaky(&peer_id, 24, 90.0, &mut db));
+        // Observations outside of the window don't count
+        for hours_ago in 48..58 {
+            observe(&peer_id, hours_ago, true, &mut db);
+        }
+        assert!(peer_is_flaky(&peer_id, 24, 90.0, &mut db));
+        assert!(!peer_is_flaky(&peer_id, 72, 90.0, &mut db));
+        let removed = remove_peer_observations_before(
+            &mut db,
+            (Utc::now() - Duration::hours(24)).timestamp(),
+        );
+        assert_eq!(removed, 10);
+        assert!(peer_is_flaky(&peer_id, 72, 90.0, &mut db));
+    }
+}
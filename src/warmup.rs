@@ -0,0 +1,148 @@
+use crate::gossip::get_competitor_fees;
+use crate::rpc::Client;
+use crate::Cli;
+use clap::ValueEnum;
+use log::warn;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum WarmupPolicy {
+    /// Leave the fee of new channels as the node set it
+    None,
+    /// Start new channels at the warmup fee
+    Fixed,
+    /// Start new channels at the median fee of our other channels
+    Median,
+    /// Start new channels at a percentile of the fees other nodes charge into the peer
+    Competitor,
+}
+
+/// Every reason of a warmup decision starts with this
+pub const WARMUP_REASON: &str = "New channel starts at";
+
+pub fn median(fees: &[u32]) -> Option<u32> {
+    if fees.is_empty() {
+        return None;
+    }
+    let mut fees = fees.to_vec();
+    fees.sort_unstable();
+    let middle = fees.len() / 2;
+    if fees.len() % 2 == 1 {
+        Some(fees[middle])
+    } else {
+        Some(((u64::from(fees[middle - 1]) + u64::from(fees[middle])) / 2) as u32)
+    }
+}
+/// Determines the fee that a new channel starts at and why. Falls back to the warmup fee when
+/// there are no other channels or competitors to derive it from or the competitors can't be
+/// queried. `node_id` is only known when it is needed to find competitors.
+pub async fn warmup_fee(
+    cli: &Cli,
+    peer_id: &str,
+    other_fees: &[u32],
+    node_id: Option<&String>,
+    client: &mut Client,
+) -> (u32, String) {
+    let fixed = (cli.warmup_fee, format!("{} warmup fee", WARMUP_REASON));
+    match cli.warmup_policy {
+        WarmupPolicy::None | WarmupPolicy::Fixed => fixed,
+        WarmupPolicy::Median => match median(other_fees) {
+            Some(fee) => (
+                fee,
+                format!("{} median fee of other channels", WARMUP_REASON),
+            ),
+            None => fixed,
+        },
+        WarmupPolicy::Competitor => {
+            let competitors = match node_id {
+                Some(node_id) => match get_competitor_fees(peer_id, node_id, client).await {
+                    Ok(competitors) => Some(competitors),
+                    Err(e) => {
+                        warn!(
+                            "{}: Falling back to warmup fee because competitors are unknown: {:#}",
+                            peer_id, e
+                        );
+                        None
+                    }
+                },
+                None => None,
+            };
+            match competitors.and_then(|competitors| competitors.percentile(cli.warmup_percentile))
+            {
+                Some(fee) => (
+                    fee,
+                    format!(
+                        "{} {}th percentile of competitors",
+                        WARMUP_REASON, cli.warmup_percentile
+                    ),
+                ),
+                None => fixed,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{query_has_applied_decision, store_decision, Decision};
+    use crate::migrations::migrate;
+    use rusqlite::Connection;
+    use tokio::sync::broadcast;
+    #[test]
+    fn median_of_fees() {
+        assert_eq!(median(&[]), None);
+        assert_eq!(median(&[300, 100, 200]), Some(200));
+        assert_eq!(median(&[400, 100, 200, 300]), Some(250));
+        assert_eq!(median(&[u32::MAX, u32::MAX]), Some(u32::MAX));
+    }
+    #[test]
+    fn warm_up_channels_that_went_offline_first() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db);
+        let decisions = broadcast::channel(64).0;
+        let went_offline = Decision {
+            channel_id: "ab".repeat(32),
+            short_channel_id: String::from("1x2x3"),
+            time: 1660000000,
+            aggregates: None,
+            reason: String::from("Peer went offline"),
+            proposed_fee: Some(5000),
+            applied_fee: Some(5000),
+            success: Some(true),
+        };
+        store_decision(&mut db, &went_offline, &decisions);
+        let channel_id = &went_offline.channel_id;
+        assert!(!query_has_applied_decision(
+            channel_id,
+            WARMUP_REASON,
+            &mut db
+        ));
+        let failed = Decision {
+            reason: format!("{} warmup fee", WARMUP_REASON),
+            success: Some(false),
+            ..went_offline.clone()
+        };
+        store_decision(&mut db, &failed, &decisions);
+        // A warmup fee that couldn't be set is tried again
+        assert!(!query_has_applied_decision(
+            channel_id,
+            WARMUP_REASON,
+            &mut db
+        ));
+        let warmup = Decision {
+            success: Some(true),
+            ..failed
+        };
+        store_decision(&mut db, &warmup, &decisions);
+        assert!(query_has_applied_decision(
+            channel_id,
+            WARMUP_REASON,
+            &mut db
+        ));
+        assert!(!query_has_applied_decision(
+            &"cd".repeat(32),
+            WARMUP_REASON,
+            &mut db
+        ));
+    }
+}